@@ -0,0 +1,30 @@
+//! SQLite loadable extension registering the functions of `tdigest::sqlite`.
+//!
+//! ```sh
+//! cargo build --release --manifest-path sqlite/Cargo.toml
+//! sqlite3 logs.db ".load sqlite/target/release/libtdigest_sqlite" \
+//!     "SELECT route, tdigest_quantile(tdigest(latency), 0.99) FROM requests GROUP BY route"
+//! ```
+
+use std::os::raw::{c_char, c_int};
+
+use rusqlite::{ffi, Connection, Result};
+
+/// Entry point called by SQLite when loading the extension.
+///
+/// # Safety
+///
+/// Must only be called by SQLite, with the arguments of `sqlite3_load_extension`.
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_extension_init(
+    db: *mut ffi::sqlite3,
+    pz_err_msg: *mut *mut c_char,
+    p_api: *mut ffi::sqlite3_api_routines,
+) -> c_int {
+    Connection::extension_init2(db, pz_err_msg, p_api, init)
+}
+
+fn init(conn: Connection) -> Result<bool> {
+    tdigest::sqlite::register_functions(&conn)?;
+    Ok(false)
+}
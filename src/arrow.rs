@@ -0,0 +1,268 @@
+//! Conversions between digests and Apache Arrow arrays, behind the `use_arrow` feature.
+//!
+//! Values are read from `Float64Array`s in place, one run of non-null values at a time. A
+//! column of digests is a `StructArray` of the type returned by `digest_data_type`:
+//!
+//! ```text
+//! max_size  UInt64
+//! scale     UInt8, 0 Quadratic, 1 K0, 2 K1, 3 K2, 4 K3
+//! count     Float64
+//! sum       Float64
+//! min       Float64, null while empty
+//! max       Float64, null while empty
+//! centroids List<Struct<mean: Float64, weight: Float64>>
+//! ```
+
+use std::sync::Arc;
+
+use arrow_array::{Array, ArrayRef, Float64Array, ListArray, StructArray, UInt64Array, UInt8Array};
+use arrow_buffer::OffsetBuffer;
+use arrow_schema::{DataType, Field, Fields};
+
+use crate::encoding::{scale_from_byte, scale_to_byte};
+use crate::{Centroid, TDigest, TDigestError, MAX_DECODED_SIZE};
+
+/// Builds a digest of `max_size` from the non-null values of `array`.
+pub fn from_float64_array(array: &Float64Array, max_size: usize) -> TDigest {
+    let mut digest = TDigest::new_with_size(max_size);
+    insert_float64_array(&mut digest, array);
+    digest.flush();
+    digest
+}
+
+/// Inserts the non-null values of `array`, see `TDigest::insert_many`.
+pub fn insert_float64_array(digest: &mut TDigest, array: &Float64Array) {
+    try_insert_float64_array(digest, array).unwrap_or_else(|err| panic!("{}", err))
+}
+
+/// Inserts the non-null values of `array`, see `TDigest::try_insert_many`.
+///
+/// Nothing is inserted if any of them is refused, and infinities are clamped to the bounds of all
+/// of them whatever the nulls in between.
+pub fn try_insert_float64_array(digest: &mut TDigest, array: &Float64Array) -> Result<(), TDigestError> {
+    let values: &[f64] = array.values();
+    match array.nulls() {
+        Some(nulls) if nulls.null_count() > 0 => {
+            let slices: Vec<(usize, usize)> = nulls.valid_slices().collect();
+            digest.try_insert_values(
+                slices
+                    .iter()
+                    .flat_map(|&(start, end)| values[start..end].iter().cloned()),
+            )
+        }
+        _ => digest.try_insert_many(values),
+    }
+}
+
+/// Fields of the struct type of a column of digests.
+pub fn digest_fields() -> Fields {
+    Fields::from(vec![
+        Field::new("max_size", DataType::UInt64, false),
+        Field::new("scale", DataType::UInt8, false),
+        Field::new("count", DataType::Float64, false),
+        Field::new("sum", DataType::Float64, false),
+        Field::new("min", DataType::Float64, true),
+        Field::new("max", DataType::Float64, true),
+        Field::new("centroids", DataType::List(Arc::new(centroid_field())), false),
+    ])
+}
+
+/// Type of a column of digests.
+pub fn digest_data_type() -> DataType {
+    DataType::Struct(digest_fields())
+}
+
+fn centroid_fields() -> Fields {
+    Fields::from(vec![
+        Field::new("mean", DataType::Float64, false),
+        Field::new("weight", DataType::Float64, false),
+    ])
+}
+
+fn centroid_field() -> Field {
+    Field::new("item", DataType::Struct(centroid_fields()), false)
+}
+
+/// Encodes `digests` as a column, one row per digest.
+pub fn to_struct_array(digests: &[TDigest]) -> StructArray {
+    let digests: Vec<_> = digests.iter().map(|d| d.merged()).collect();
+    let bound = |digest: &TDigest, value: f64| if digest.is_empty() { None } else { Some(value) };
+
+    let max_sizes = UInt64Array::from(digests.iter().map(|d| d.max_size() as u64).collect::<Vec<_>>());
+    let scales = UInt8Array::from(digests.iter().map(|d| scale_to_byte(d.scale())).collect::<Vec<_>>());
+    let counts = Float64Array::from(digests.iter().map(|d| d.count()).collect::<Vec<_>>());
+    let sums = Float64Array::from(digests.iter().map(|d| d.sum()).collect::<Vec<_>>());
+    let mins: Float64Array = digests.iter().map(|d| bound(d, d.min())).collect();
+    let maxs: Float64Array = digests.iter().map(|d| bound(d, d.max())).collect();
+
+    let centroids = digests.iter().flat_map(|d| d.centroids.iter());
+    let means = Float64Array::from(centroids.clone().map(|c| c.mean()).collect::<Vec<_>>());
+    let weights = Float64Array::from(centroids.map(|c| c.weight()).collect::<Vec<_>>());
+    let values = StructArray::new(
+        centroid_fields(),
+        vec![Arc::new(means) as ArrayRef, Arc::new(weights) as ArrayRef],
+        None,
+    );
+    let offsets = OffsetBuffer::<i32>::from_lengths(digests.iter().map(|d| d.centroids.len()));
+    let centroids = ListArray::new(Arc::new(centroid_field()), offsets, Arc::new(values), None);
+
+    StructArray::new(
+        digest_fields(),
+        vec![
+            Arc::new(max_sizes) as ArrayRef,
+            Arc::new(scales) as ArrayRef,
+            Arc::new(counts) as ArrayRef,
+            Arc::new(sums) as ArrayRef,
+            Arc::new(mins) as ArrayRef,
+            Arc::new(maxs) as ArrayRef,
+            Arc::new(centroids) as ArrayRef,
+        ],
+        None,
+    )
+}
+
+/// Decodes a column written by `to_struct_array`, failing on null rows or invalid digests, including
+/// those of a `max_size` above `MAX_DECODED_SIZE`.
+///
+/// Columns are found by name, so field metadata and nullability may differ.
+pub fn from_struct_array(array: &StructArray) -> Result<Vec<TDigest>, TDigestError> {
+    if array.null_count() > 0 {
+        return Err(TDigestError::InvalidEncoding("null digest"));
+    }
+
+    let max_sizes: &UInt64Array = column(array, "max_size")?;
+    let scales: &UInt8Array = column(array, "scale")?;
+    let counts: &Float64Array = column(array, "count")?;
+    let sums: &Float64Array = column(array, "sum")?;
+    let mins: &Float64Array = column(array, "min")?;
+    let maxs: &Float64Array = column(array, "max")?;
+    let centroids: &ListArray = column(array, "centroids")?;
+
+    let values = centroids
+        .values()
+        .as_any()
+        .downcast_ref::<StructArray>()
+        .ok_or(TDigestError::InvalidEncoding("unexpected type of centroids"))?;
+    let means: &Float64Array = column(values, "mean")?;
+    let weights: &Float64Array = column(values, "weight")?;
+
+    let mut digests: Vec<TDigest> = Vec::with_capacity(array.len());
+    for i in 0..array.len() {
+        let range = centroids.value_offsets()[i] as usize..centroids.value_offsets()[i + 1] as usize;
+        let row: Vec<Centroid> = range.map(|k| Centroid::new(means.value(k), weights.value(k))).collect();
+
+        let bound = |bounds: &Float64Array| if bounds.is_null(i) { f64::NAN } else { bounds.value(i) };
+        let max_size = usize::try_from(max_sizes.value(i))
+            .ok()
+            .filter(|&max_size| max_size <= MAX_DECODED_SIZE)
+            .ok_or(TDigestError::InvalidEncoding("max_size out of range"))?;
+
+        let digest = TDigest::try_new(row, sums.value(i), counts.value(i), bound(maxs), bound(mins), max_size)?;
+        digests.push(digest.with_scale(scale_from_byte(scales.value(i))?));
+    }
+
+    Ok(digests)
+}
+
+/// Column `name` of `array` as a `T`, which must have no nulls unless it is a bound.
+fn column<'a, T: Array + 'static>(array: &'a StructArray, name: &'static str) -> Result<&'a T, TDigestError> {
+    let column = array
+        .column_by_name(name)
+        .and_then(|c| c.as_any().downcast_ref::<T>())
+        .ok_or(TDigestError::InvalidEncoding("missing or mistyped digest column"))?;
+
+    if column.null_count() > 0 && name != "min" && name != "max" {
+        return Err(TDigestError::InvalidEncoding("null in a digest column"));
+    }
+    Ok(column)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{NonFinitePolicy, Scale};
+
+    #[test]
+    fn test_from_float64_array() {
+        let values: Float64Array = (1..=10_000)
+            .map(|v| if v % 10 == 0 { None } else { Some(f64::from(v)) })
+            .collect();
+        let t = from_float64_array(&values, 100);
+
+        assert_eq!(t.count(), 9000.0);
+        assert_eq!(t.max(), 9999.0);
+        let ans = t.estimate_quantile(0.5);
+        let expected: f64 = 5000.0;
+        let percentage: f64 = (expected - ans).abs() / expected;
+        assert!(percentage < 0.01);
+
+        let sliced = values.slice(100, 200);
+        assert_eq!(from_float64_array(&sliced, 100).count(), 180.0);
+    }
+
+    #[test]
+    fn test_insert_is_all_or_nothing() {
+        let values = Float64Array::from(vec![Some(1.0), None, Some(2.0), None, Some(f64::NAN)]);
+        let mut t = TDigest::new_with_size(100).with_non_finite_policy(NonFinitePolicy::Reject);
+        assert!(try_insert_float64_array(&mut t, &values).is_err());
+        assert!(t.is_empty());
+
+        let mut t = TDigest::new_with_size(100);
+        try_insert_float64_array(&mut t, &values).unwrap();
+        assert_eq!((t.count(), t.rejected()), (2.0, 1));
+
+        // Infinities are clamped to the bounds of the whole array, whatever the nulls in between.
+        let clamped = |values: Float64Array| {
+            let mut t = TDigest::new_with_size(100).with_non_finite_policy(NonFinitePolicy::Clamp);
+            try_insert_float64_array(&mut t, &values).unwrap();
+            t.flush();
+            t
+        };
+        let with_nulls = clamped(Float64Array::from(vec![Some(f64::INFINITY), None, Some(1.0)]));
+        assert_eq!(
+            (with_nulls.count(), with_nulls.max(), with_nulls.rejected()),
+            (2.0, 1.0, 0)
+        );
+        assert_eq!(with_nulls, clamped(Float64Array::from(vec![f64::INFINITY, 1.0])));
+    }
+
+    #[test]
+    fn test_struct_array_round_trip() {
+        let digests = vec![
+            TDigest::new_with_size(100).merge_unsorted((1..=1000).map(f64::from).collect()),
+            TDigest::new_with_size(20),
+            TDigest::new_with_size(50)
+                .with_scale(Scale::K3)
+                .merge_sorted(vec![1.0, 2.0, 3.0]),
+        ];
+
+        let array = to_struct_array(&digests);
+        assert_eq!(array.data_type(), &digest_data_type());
+        assert!(array.column_by_name("min").unwrap().is_null(1));
+
+        assert_eq!(from_struct_array(&array).unwrap(), digests);
+        assert_eq!(from_struct_array(&array.slice(1, 2)).unwrap(), digests[1..].to_vec());
+    }
+
+    #[test]
+    fn test_rejects_invalid_columns() {
+        let array = to_struct_array(&[TDigest::new_with_size(100).merge_sorted(vec![1.0, 2.0])]);
+
+        let (fields, mut columns, _) = array.clone().into_parts();
+        columns[2] = Arc::new(Float64Array::from(vec![3.0]));
+        let mismatched = StructArray::new(fields.clone(), columns, None);
+        assert!(from_struct_array(&mismatched).is_err());
+
+        let (fields, mut columns, _) = array.clone().into_parts();
+        columns[0] = Arc::new(UInt64Array::from(vec![1_000_000_000_000]));
+        let huge = StructArray::new(fields.clone(), columns, None);
+        assert_eq!(
+            from_struct_array(&huge),
+            Err(TDigestError::InvalidEncoding("max_size out of range"))
+        );
+
+        let (_, columns, _) = array.into_parts();
+        let missing = StructArray::new(Fields::from(fields[1..].to_vec()), columns[1..].to_vec(), None);
+        assert!(from_struct_array(&missing).is_err());
+    }
+}
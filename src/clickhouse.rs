@@ -0,0 +1,153 @@
+//! Aggregate state of the ClickHouse `quantileTDigest` family of functions.
+//!
+//! The state, as produced by `quantileTDigestState` and written by `QuantileTDigest::serialize`,
+//! is a varint centroid count followed by the centroids, little-endian:
+//!
+//! ```text
+//! varint n, n * (f32 mean, f32 count)
+//! ```
+//!
+//! It differs from a `TDigest` in a few ways:
+//!
+//! - ClickHouse compresses with its own rule, driven by an epsilon of 0.01 and at most 2048
+//!   centroids, rather than by `max_size` and a scale function. The digests read from a state
+//!   keep its centroids, and the `max_size` given to `from_state` applies to later merges.
+//! - Means and counts are single precision, so encoding rounds them.
+//! - No bounds or sum are stored: a decoded digest has its first and last means as minimum
+//!   and maximum, and its sum is recomputed from the centroids.
+//! - ClickHouse interpolates quantiles between centroid means, so it can answer differently
+//!   from `estimate_quantile` for the same centroids.
+
+use crate::bytes::{write_varint, Reader};
+use crate::{Centroid, TDigest, TDigestError};
+
+/// Most centroids ClickHouse accepts in a state.
+const MAX_CENTROIDS_DESERIALIZE: usize = 65_536;
+
+/// Encodes `digest` as a `quantileTDigest` state.
+///
+/// Fails if the digest has more centroids than ClickHouse accepts.
+pub fn to_state(digest: &TDigest) -> Result<Vec<u8>, TDigestError> {
+    let digest = digest.merged();
+    if digest.centroids.len() > MAX_CENTROIDS_DESERIALIZE {
+        return Err(TDigestError::InvalidArgument(
+            "too many centroids for a ClickHouse state",
+        ));
+    }
+
+    let mut bytes: Vec<u8> = Vec::with_capacity(3 + 8 * digest.centroids.len());
+    write_varint(&mut bytes, digest.centroids.len() as u64);
+    for centroid in digest.centroids.iter() {
+        let mean = centroid.mean().clamp(f64::from(f32::MIN), f64::from(f32::MAX)) as f32;
+        bytes.extend_from_slice(&mean.to_le_bytes());
+        bytes.extend_from_slice(&(centroid.weight() as f32).to_le_bytes());
+    }
+
+    Ok(bytes)
+}
+
+/// Decodes a `quantileTDigest` state into a digest of `max_size`.
+///
+/// Like ClickHouse, centroids with a NaN mean are dropped and the others are sorted. Fails on
+/// invalid counts, infinite means or trailing bytes.
+pub fn from_state(bytes: &[u8], max_size: usize) -> Result<TDigest, TDigestError> {
+    let mut reader = Reader::new(bytes);
+
+    let n = reader.varint()?;
+    if n > MAX_CENTROIDS_DESERIALIZE as u64 {
+        return Err(TDigestError::InvalidEncoding("too many centroids"));
+    }
+    let n = n as usize;
+
+    let centroids_bytes = reader.take(8 * n)?;
+    reader.finish()?;
+
+    let mut centroids: Vec<Centroid> = Vec::with_capacity(n);
+    let mut centroid_reader = Reader::new(centroids_bytes);
+    for _ in 0..n {
+        let mean = f64::from(f32::from_le_bytes(centroid_reader.array()?));
+        let weight = f64::from(f32::from_le_bytes(centroid_reader.array()?));
+        if weight.is_nan() || weight <= 0.0 {
+            return Err(TDigestError::InvalidWeight(weight));
+        }
+
+        if !mean.is_nan() {
+            centroids.push(Centroid::new(mean, weight));
+        }
+    }
+    centroids.sort();
+
+    let count: f64 = centroids.iter().map(|c| c.weight()).sum();
+    let sum: f64 = centroids.iter().map(|c| c.mean() * c.weight()).sum();
+    let (min, max) = match (centroids.first(), centroids.last()) {
+        (Some(first), Some(last)) => (first.mean(), last.mean()),
+        _ => (f64::NAN, f64::NAN),
+    };
+    TDigest::try_new(centroids, sum, count, max, min, max_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::hex;
+
+    // Laid out following `QuantileTDigest::serialize`: the state of the values 0, 1 and 2,
+    // then a compressed state with centroids of counts 1, 3.5 and 1.5.
+    const THREE_VALUES: &str = "03000000000000803f0000803f0000803f000000400000803f";
+    const WEIGHTED: &str = "030000803f0000803f0000204000006040000080400000c03f";
+
+    #[test]
+    fn test_fixtures() {
+        let t = from_state(&hex(THREE_VALUES), 100).unwrap();
+        assert_eq!(t, TDigest::new_with_size(100).merge_sorted(vec![0.0, 1.0, 2.0]));
+        assert_eq!(to_state(&t).unwrap(), hex(THREE_VALUES));
+
+        let t = from_state(&hex(WEIGHTED), 100).unwrap();
+        assert_eq!(t.count(), 6.0);
+        assert_eq!(t.sum(), 1.0 + 2.5 * 3.5 + 4.0 * 1.5);
+        assert_eq!((t.min(), t.max()), (1.0, 4.0));
+        assert_eq!(to_state(&t).unwrap(), hex(WEIGHTED));
+
+        assert!(from_state(&hex("00"), 100).unwrap().is_empty());
+        assert_eq!(to_state(&TDigest::new_with_size(100)).unwrap(), hex("00"));
+    }
+
+    #[test]
+    fn test_merge_with_digest() {
+        let t = TDigest::new_with_size(100).merge_unsorted((1..=5000).map(f64::from).collect());
+        let state = from_state(&to_state(&t).unwrap(), 100).unwrap();
+        let other = TDigest::new_with_size(100).merge_unsorted((5001..=10_000).map(f64::from).collect());
+
+        let merged = TDigest::merge_digests(vec![state, other]);
+        assert_eq!(merged.count(), 10_000.0);
+        let ans = merged.estimate_quantile(0.5);
+        let expected: f64 = 5000.0;
+        let percentage: f64 = (expected - ans).abs() / expected;
+        assert!(percentage < 0.01);
+    }
+
+    #[test]
+    fn test_like_clickhouse_deserialize() {
+        // Unsorted, with a NaN mean.
+        let state = [
+            &[3u8][..],
+            &2.0f32.to_le_bytes(),
+            &1.0f32.to_le_bytes(),
+            &f32::NAN.to_le_bytes(),
+            &1.0f32.to_le_bytes(),
+            &1.0f32.to_le_bytes(),
+            &1.0f32.to_le_bytes(),
+        ]
+        .concat();
+        let t = from_state(&state, 100).unwrap();
+        assert_eq!(t, TDigest::new_with_size(100).merge_sorted(vec![1.0, 2.0]));
+
+        let bytes = hex(THREE_VALUES);
+        assert!(from_state(&bytes[..bytes.len() - 1], 100).is_err());
+        assert!(from_state(&[bytes.as_slice(), &[0]].concat(), 100).is_err());
+
+        let mut zero_count = bytes;
+        zero_count[5..9].copy_from_slice(&0.0f32.to_le_bytes());
+        assert_eq!(from_state(&zero_count, 100), Err(TDigestError::InvalidWeight(0.0)));
+    }
+}
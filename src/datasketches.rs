@@ -0,0 +1,283 @@
+//! Binary format of the Apache DataSketches t-digest, shared by its C++ and Java versions.
+//!
+//! The layout is little-endian, starting with a preamble of one or two 8 byte words:
+//!
+//! ```text
+//! u8 preamble longs, u8 serial version 1, u8 sketch type 20, u16 k, u8 flags, u16 unused
+//! empty:    nothing else, flag 1
+//! single:   f64 value, flag 2, for a digest holding a single value
+//! multiple: u32 n, u32 m, f64 min, f64 max, n * (f64 mean, u64 weight), m * f64 value
+//! ```
+//!
+//! The `m` values are those still buffered by the sketch, they are inserted on decoding.
+//! `to_bytes` always writes an empty buffer.
+//!
+//! `k` maps to `max_size` and must be in `[10, 65535]`. Flag 4, set by sketches merged in
+//! reverse order, does not change the content and is ignored. Only the `double` sketch is
+//! supported. The layout stores no sum, it is recomputed from the centroids on decoding.
+
+use crate::bytes::Reader;
+use crate::{Centroid, TDigest, TDigestError};
+
+const PREAMBLE_LONGS_EMPTY_OR_SINGLE: u8 = 1;
+const PREAMBLE_LONGS_MULTIPLE: u8 = 2;
+const SERIAL_VERSION: u8 = 1;
+const SKETCH_TYPE: u8 = 20;
+
+const IS_EMPTY: u8 = 1;
+const IS_SINGLE_VALUE: u8 = 2;
+const REVERSE_MERGE: u8 = 4;
+
+const MIN_K: usize = 10;
+
+/// Encodes `digest` like `tdigest<double>::serialize`.
+///
+/// Fails if `max_size` is out of range or a weight is not an integer.
+pub fn to_bytes(digest: &TDigest) -> Result<Vec<u8>, TDigestError> {
+    let digest = digest.merged();
+    if !(MIN_K..=u16::MAX as usize).contains(&digest.max_size()) {
+        return Err(TDigestError::InvalidArgument("k must be in [10, 65535]"));
+    }
+
+    let single = digest.count() == 1.0 && digest.centroids.len() == 1;
+    let (preamble_longs, flags) = if digest.is_empty() {
+        (PREAMBLE_LONGS_EMPTY_OR_SINGLE, IS_EMPTY)
+    } else if single {
+        (PREAMBLE_LONGS_EMPTY_OR_SINGLE, IS_SINGLE_VALUE)
+    } else {
+        (PREAMBLE_LONGS_MULTIPLE, 0)
+    };
+
+    let mut bytes: Vec<u8> = Vec::with_capacity(32 + 16 * digest.centroids.len());
+    bytes.extend_from_slice(&[preamble_longs, SERIAL_VERSION, SKETCH_TYPE]);
+    bytes.extend_from_slice(&(digest.max_size() as u16).to_le_bytes());
+    bytes.push(flags);
+    bytes.extend_from_slice(&0u16.to_le_bytes());
+
+    if digest.is_empty() {
+        return Ok(bytes);
+    } else if single {
+        bytes.extend_from_slice(&digest.centroids[0].mean().to_le_bytes());
+        return Ok(bytes);
+    }
+
+    bytes.extend_from_slice(&(digest.centroids.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&0u32.to_le_bytes());
+    bytes.extend_from_slice(&digest.min().to_le_bytes());
+    bytes.extend_from_slice(&digest.max().to_le_bytes());
+    for centroid in digest.centroids.iter() {
+        let weight = centroid.weight();
+        if !(weight.fract() == 0.0 && weight <= u64::MAX as f64) {
+            return Err(TDigestError::InvalidArgument("centroid weights must be integers"));
+        }
+
+        bytes.extend_from_slice(&centroid.mean().to_le_bytes());
+        bytes.extend_from_slice(&(weight as u64).to_le_bytes());
+    }
+
+    Ok(bytes)
+}
+
+/// Decodes a `tdigest<double>` sketch, failing on anything else or on trailing bytes.
+pub fn from_bytes(bytes: &[u8]) -> Result<TDigest, TDigestError> {
+    let mut reader = Reader::new(bytes);
+
+    let [preamble_longs, serial_version, sketch_type] = reader.array()?;
+    let k = usize::from(u16::from_le_bytes(reader.array()?));
+    let [flags] = reader.array()?;
+    let _unused = reader.array::<2>()?;
+
+    if serial_version != SERIAL_VERSION {
+        return Err(TDigestError::InvalidEncoding("unsupported serial version"));
+    }
+    if sketch_type != SKETCH_TYPE {
+        return Err(TDigestError::InvalidEncoding("not a t-digest sketch"));
+    }
+    if flags & !(IS_EMPTY | IS_SINGLE_VALUE | REVERSE_MERGE) != 0 {
+        return Err(TDigestError::InvalidEncoding("unknown flags"));
+    }
+    if k < MIN_K {
+        return Err(TDigestError::InvalidEncoding("k must be at least 10"));
+    }
+
+    let expected_preamble_longs = if flags & (IS_EMPTY | IS_SINGLE_VALUE) != 0 {
+        PREAMBLE_LONGS_EMPTY_OR_SINGLE
+    } else {
+        PREAMBLE_LONGS_MULTIPLE
+    };
+    if preamble_longs != expected_preamble_longs {
+        return Err(TDigestError::InvalidEncoding("preamble does not match flags"));
+    }
+
+    if flags & IS_EMPTY != 0 {
+        reader.finish()?;
+        return TDigest::try_new(Vec::new(), 0.0, 0.0, f64::NAN, f64::NAN, k);
+    } else if flags & IS_SINGLE_VALUE != 0 {
+        let value = f64::from_le_bytes(reader.array()?);
+        reader.finish()?;
+        return TDigest::try_new(vec![Centroid::new(value, 1.0)], value, 1.0, value, value, k);
+    }
+
+    let n = u32::from_le_bytes(reader.array()?) as usize;
+    let m = u32::from_le_bytes(reader.array()?) as usize;
+    let min = f64::from_le_bytes(reader.array()?);
+    let max = f64::from_le_bytes(reader.array()?);
+
+    let centroids_bytes = reader.take(n.saturating_mul(16))?;
+    let buffer_bytes = reader.take(m.saturating_mul(8))?;
+    reader.finish()?;
+
+    let mut centroids: Vec<Centroid> = Vec::with_capacity(n);
+    let mut count: f64 = 0.0;
+    let mut sum: f64 = 0.0;
+    let mut centroid_reader = Reader::new(centroids_bytes);
+    for _ in 0..n {
+        let mean = f64::from_le_bytes(centroid_reader.array()?);
+        let weight = u64::from_le_bytes(centroid_reader.array()?) as f64;
+
+        count += weight;
+        sum += weight * mean;
+        centroids.push(Centroid::new(mean, weight));
+    }
+
+    let mut buffer: Vec<f64> = Vec::with_capacity(m);
+    let mut buffer_reader = Reader::new(buffer_bytes);
+    for _ in 0..m {
+        let value = f64::from_le_bytes(buffer_reader.array()?);
+        if !(min <= value && value <= max) {
+            return Err(TDigestError::InvalidBounds { min, max });
+        }
+        buffer.push(value);
+    }
+
+    // The bounds also cover the buffered values, so they only apply to the centroids if there are some.
+    let mut digest = if centroids.is_empty() && !buffer.is_empty() {
+        TDigest::new_with_size(k)
+    } else {
+        TDigest::try_new(centroids, sum, count, max, min, k)?
+    };
+    digest.insert_many(&buffer);
+    digest.flush();
+    Ok(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::{digest, hex};
+
+    // Laid out by hand following `tdigest<double>::serialize` for sketches of k 100.
+    const EMPTY: &str = "0101146400010000";
+    const SINGLE: &str = concat!("0101146400020000", "0000000000001440");
+    const MULTIPLE: &str = concat!(
+        "0201146400000000",
+        "0300000000000000",
+        "000000000000f03f",
+        "0000000000000840",
+        "000000000000f03f",
+        "0100000000000000",
+        "0000000000000040",
+        "0200000000000000",
+        "0000000000000840",
+        "0100000000000000",
+    );
+    // `MULTIPLE` with 2.5 and 1.5 still buffered.
+    const BUFFERED: &str = concat!(
+        "0201146400000000",
+        "0300000002000000",
+        "000000000000f03f",
+        "0000000000000840",
+        "000000000000f03f",
+        "0100000000000000",
+        "0000000000000040",
+        "0200000000000000",
+        "0000000000000840",
+        "0100000000000000",
+        "0000000000000440",
+        "000000000000f83f",
+    );
+
+    #[test]
+    fn test_golden_vectors() {
+        let empty = TDigest::new_with_size(100);
+        let single = TDigest::new_with_size(100).merge_sorted(vec![5.0]);
+
+        assert_eq!(to_bytes(&empty).unwrap(), hex(EMPTY));
+        assert_eq!(to_bytes(&single).unwrap(), hex(SINGLE));
+        assert_eq!(to_bytes(&digest()).unwrap(), hex(MULTIPLE));
+
+        assert_eq!(from_bytes(&hex(EMPTY)).unwrap(), empty);
+        assert_eq!(from_bytes(&hex(SINGLE)).unwrap(), single);
+        assert_eq!(from_bytes(&hex(MULTIPLE)).unwrap(), digest());
+    }
+
+    #[test]
+    fn test_buffered_values() {
+        let mut expected = digest();
+        expected.insert_many(&[2.5, 1.5]);
+        expected.flush();
+        let t = from_bytes(&hex(BUFFERED)).unwrap();
+        assert_eq!(t, expected);
+        assert_eq!((t.count(), t.sum()), (6.0, 12.0));
+
+        // Only buffered values, in a sketch of k 100 and bounds [1, 3].
+        let buffer_only = hex(concat!(
+            "0201146400000000",
+            "0000000002000000",
+            "000000000000f03f",
+            "0000000000000840",
+            "0000000000000840",
+            "000000000000f03f",
+        ));
+        let t = from_bytes(&buffer_only).unwrap();
+        assert_eq!((t.count(), t.min(), t.max()), (2.0, 1.0, 3.0));
+
+        let mut out_of_bounds = hex(BUFFERED);
+        let len = out_of_bounds.len();
+        out_of_bounds[len - 8..].copy_from_slice(&4.0f64.to_le_bytes());
+        assert_eq!(
+            from_bytes(&out_of_bounds),
+            Err(TDigestError::InvalidBounds { min: 1.0, max: 3.0 })
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let t = TDigest::new_with_size(200).merge_unsorted((1..=10_000).map(|v| f64::from(v).sqrt()).collect());
+        let decoded = from_bytes(&to_bytes(&t).unwrap()).unwrap();
+        assert_eq!(decoded.centroids(), t.centroids());
+        assert_eq!(decoded.estimate_quantile(0.99), t.estimate_quantile(0.99));
+
+        let mut reversed = hex(MULTIPLE);
+        reversed[5] = REVERSE_MERGE;
+        assert_eq!(from_bytes(&reversed).unwrap(), digest());
+
+        assert!(to_bytes(&TDigest::new_with_size(5)).is_err());
+        let weighted = TDigest::new_with_size(100).merge_weighted_unsorted(vec![(1.0, 0.5), (2.0, 1.0)]);
+        assert!(to_bytes(&weighted).is_err());
+    }
+
+    #[test]
+    fn test_rejects_corrupt_input() {
+        let bytes = hex(MULTIPLE);
+        for len in 0..bytes.len() {
+            assert!(from_bytes(&bytes[..len]).is_err());
+        }
+        assert!(from_bytes(&[bytes.as_slice(), &[0]].concat()).is_err());
+
+        let mut sketch_type = bytes.clone();
+        sketch_type[2] = 15;
+        assert_eq!(
+            from_bytes(&sketch_type),
+            Err(TDigestError::InvalidEncoding("not a t-digest sketch"))
+        );
+
+        let mut preamble = bytes.clone();
+        preamble[0] = 1;
+        assert!(from_bytes(&preamble).is_err());
+
+        let mut zero_weight = bytes;
+        zero_weight[40] = 0;
+        assert_eq!(from_bytes(&zero_weight), Err(TDigestError::InvalidWeight(0.0)));
+    }
+}
@@ -0,0 +1,425 @@
+//! Native binary encoding of a `TDigest`, see `TDigest::to_bytes` for the layout.
+
+use crate::bytes::{crc32, write_varint, Reader};
+use crate::{Centroid, Scale, TDigest, TDigestError, MAX_DECODED_SIZE};
+
+const MAGIC: &[u8; 4] = b"TDGT";
+const VERSION: u8 = 1;
+
+const F32_MEANS: u8 = 1;
+const INTEGRAL_WEIGHTS: u8 = 2;
+
+/// Largest integer below which every integral `f64` is exactly representable.
+const MAX_INTEGRAL_WEIGHT: f64 = 9_007_199_254_740_992.0;
+
+/// Precision of the means in the encoding of a digest.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Precision {
+    /// Means are stored exactly.
+    #[default]
+    F64,
+    /// Means are rounded to `f32`, saturating at its largest finite values.
+    F32,
+}
+
+impl TDigest {
+    /// Encodes the digest losslessly.
+    ///
+    /// The layout is little-endian and versioned:
+    ///
+    /// ```text
+    /// magic     4 bytes "TDGT"
+    /// version   u8, currently 1
+    /// flags     u8, bit 0: single precision means, bit 1: integral weights
+    /// scale     u8, 0 Quadratic, 1 K0, 2 K1, 3 K2, 4 K3
+    /// max_size  varint
+    /// count     f64
+    /// sum       f64
+    /// min       f64
+    /// max       f64
+    /// n         varint
+    /// n * (mean, weight)
+    /// checksum  u32, CRC-32 of all the preceding bytes
+    /// ```
+    ///
+    /// Means are mapped to integers preserving their order, the bits of the `f64`, or of the `f32`
+    /// in single precision, and each is stored as a varint of the difference to the previous one.
+    /// Weights are varints when all of them are integers, `f64`s otherwise. Varints are unsigned
+    /// LEB128. The non-finite policy and the rejected count are not encoded.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_bytes_with_precision(Precision::F64)
+    }
+
+    /// Encodes the digest with means of the given precision.
+    pub fn to_bytes_with_precision(&self, precision: Precision) -> Vec<u8> {
+        let digest = self.merged();
+        let integral = digest
+            .centroids
+            .iter()
+            .all(|c| c.weight().fract() == 0.0 && c.weight() <= MAX_INTEGRAL_WEIGHT);
+
+        let mut flags = 0;
+        if precision == Precision::F32 {
+            flags |= F32_MEANS;
+        }
+        if integral {
+            flags |= INTEGRAL_WEIGHTS;
+        }
+
+        let mut bytes: Vec<u8> = Vec::with_capacity(48 + 4 * digest.centroids.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.push(flags);
+        bytes.push(scale_to_byte(digest.scale()));
+        write_varint(&mut bytes, digest.max_size() as u64);
+        bytes.extend_from_slice(&digest.count().to_le_bytes());
+        bytes.extend_from_slice(&digest.sum().to_le_bytes());
+        bytes.extend_from_slice(&digest.min().to_le_bytes());
+        bytes.extend_from_slice(&digest.max().to_le_bytes());
+        write_varint(&mut bytes, digest.centroids.len() as u64);
+
+        let mut prev: u64 = 0;
+        for centroid in digest.centroids.iter() {
+            let key = match precision {
+                Precision::F64 => f64_to_key(centroid.mean()),
+                Precision::F32 => {
+                    let mean = centroid.mean().clamp(f64::from(f32::MIN), f64::from(f32::MAX));
+                    u64::from(f32_to_key(mean as f32))
+                }
+            };
+            write_varint(&mut bytes, key.wrapping_sub(prev));
+            prev = key;
+
+            if integral {
+                write_varint(&mut bytes, centroid.weight() as u64);
+            } else {
+                bytes.extend_from_slice(&centroid.weight().to_le_bytes());
+            }
+        }
+
+        let checksum = crc32(&bytes);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    /// Decodes bytes produced by `to_bytes`, failing on any corruption or trailing bytes, or on a
+    /// `max_size` above `MAX_DECODED_SIZE`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<TDigest, TDigestError> {
+        let (header, centroids) = decode(bytes)?;
+
+        let mut decoded: Vec<Centroid> = Vec::with_capacity(header.len);
+        for centroid in centroids {
+            decoded.push(centroid?);
+        }
+
+        let (min, max) = header.bounds(decoded.first().zip(decoded.last()).map(|(f, l)| (f.mean(), l.mean())));
+
+        let digest = TDigest::try_new(decoded, header.sum, header.count, max, min, header.max_size)?;
+        Ok(digest.with_scale(header.scale))
+    }
+}
+
+/// Fixed fields of an encoded digest.
+pub(crate) struct Header {
+    pub(crate) flags: u8,
+    pub(crate) scale: Scale,
+    pub(crate) max_size: usize,
+    pub(crate) count: f64,
+    pub(crate) sum: f64,
+    pub(crate) min: f64,
+    pub(crate) max: f64,
+    pub(crate) len: usize,
+}
+
+impl Header {
+    /// Bounds of the digest given its first and last means, if any.
+    pub(crate) fn bounds(&self, means: Option<(f64, f64)>) -> (f64, f64) {
+        match means {
+            // Single precision means can round past the double precision bounds.
+            Some((first, last)) if self.flags & F32_MEANS != 0 => (self.min.min(first), self.max.max(last)),
+            _ => (self.min, self.max),
+        }
+    }
+}
+
+/// Checks the checksum and decodes the header, returning a reader over the centroids.
+pub(crate) fn decode(bytes: &[u8]) -> Result<(Header, Centroids<'_>), TDigestError> {
+    if bytes.len() < MAGIC.len() + 4 {
+        return Err(TDigestError::InvalidEncoding("unexpected end of input"));
+    }
+
+    let (body, checksum) = bytes.split_at(bytes.len() - 4);
+    if &bytes[..MAGIC.len()] != MAGIC {
+        return Err(TDigestError::InvalidEncoding("bad magic number"));
+    }
+    if crc32(body) != u32::from_le_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]) {
+        return Err(TDigestError::InvalidEncoding("checksum mismatch"));
+    }
+
+    let mut reader = Reader::new(&body[MAGIC.len()..]);
+    let [version, flags, scale] = reader.array()?;
+    if version != VERSION {
+        return Err(TDigestError::InvalidEncoding("unsupported version"));
+    }
+    if flags & !(F32_MEANS | INTEGRAL_WEIGHTS) != 0 {
+        return Err(TDigestError::InvalidEncoding("unknown flags"));
+    }
+    let scale = scale_from_byte(scale)?;
+
+    let max_size = to_usize(reader.varint()?)?;
+    if max_size == 0 {
+        return Err(TDigestError::InvalidMaxSize(max_size));
+    }
+    if max_size > MAX_DECODED_SIZE {
+        return Err(TDigestError::InvalidEncoding("max_size out of range"));
+    }
+
+    let count = f64::from_le_bytes(reader.array()?);
+    let sum = f64::from_le_bytes(reader.array()?);
+    let min = f64::from_le_bytes(reader.array()?);
+    let max = f64::from_le_bytes(reader.array()?);
+
+    // Every centroid takes at least two bytes, which bounds any allocation by the input size.
+    let len = to_usize(reader.varint()?)?;
+    if len > reader.remaining() / 2 {
+        return Err(TDigestError::InvalidEncoding("unexpected end of input"));
+    }
+    if len == 0 {
+        reader.finish()?;
+    }
+
+    let header = Header {
+        flags,
+        scale,
+        max_size,
+        count,
+        sum,
+        min,
+        max,
+        len,
+    };
+    let centroids = Centroids {
+        reader,
+        flags,
+        remaining: len,
+        prev: 0,
+    };
+    Ok((header, centroids))
+}
+
+/// Decodes the centroids following the header, then checks that nothing follows them.
+#[derive(Clone)]
+pub(crate) struct Centroids<'a> {
+    reader: Reader<'a>,
+    flags: u8,
+    remaining: usize,
+    prev: u64,
+}
+
+impl<'a> Centroids<'a> {
+    fn next_centroid(&mut self) -> Result<Centroid, TDigestError> {
+        let key = self.prev.wrapping_add(self.reader.varint()?);
+        self.prev = key;
+
+        let mean = if self.flags & F32_MEANS != 0 {
+            let key = u32::try_from(key).map_err(|_| TDigestError::InvalidEncoding("invalid mean"))?;
+            f64::from(f32_from_key(key))
+        } else {
+            f64_from_key(key)
+        };
+
+        let weight = if self.flags & INTEGRAL_WEIGHTS != 0 {
+            self.reader.varint()? as f64
+        } else {
+            f64::from_le_bytes(self.reader.array()?)
+        };
+
+        self.remaining -= 1;
+        if self.remaining == 0 {
+            self.reader.finish()?;
+        }
+        Ok(Centroid::new(mean, weight))
+    }
+}
+
+impl<'a> Iterator for Centroids<'a> {
+    type Item = Result<Centroid, TDigestError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        let centroid = self.next_centroid();
+        if centroid.is_err() {
+            self.remaining = 0;
+        }
+        Some(centroid)
+    }
+}
+
+fn to_usize(value: u64) -> Result<usize, TDigestError> {
+    usize::try_from(value).map_err(|_| TDigestError::InvalidEncoding("length out of range"))
+}
+
+pub(crate) fn scale_to_byte(scale: Scale) -> u8 {
+    match scale {
+        Scale::Quadratic => 0,
+        Scale::K0 => 1,
+        Scale::K1 => 2,
+        Scale::K2 => 3,
+        Scale::K3 => 4,
+    }
+}
+
+pub(crate) fn scale_from_byte(byte: u8) -> Result<Scale, TDigestError> {
+    match byte {
+        0 => Ok(Scale::Quadratic),
+        1 => Ok(Scale::K0),
+        2 => Ok(Scale::K1),
+        3 => Ok(Scale::K2),
+        4 => Ok(Scale::K3),
+        _ => Err(TDigestError::InvalidEncoding("unknown scale")),
+    }
+}
+
+/// Maps `value` to an integer with the same order, negative values below positive ones.
+fn f64_to_key(value: f64) -> u64 {
+    let bits = value.to_bits();
+    if bits >> 63 == 1 {
+        !bits
+    } else {
+        bits | 1 << 63
+    }
+}
+
+fn f64_from_key(key: u64) -> f64 {
+    if key >> 63 == 1 {
+        f64::from_bits(key & !(1 << 63))
+    } else {
+        f64::from_bits(!key)
+    }
+}
+
+fn f32_to_key(value: f32) -> u32 {
+    let bits = value.to_bits();
+    if bits >> 31 == 1 {
+        !bits
+    } else {
+        bits | 1 << 31
+    }
+}
+
+fn f32_from_key(key: u32) -> f32 {
+    if key >> 31 == 1 {
+        f32::from_bits(key & !(1 << 31))
+    } else {
+        f32::from_bits(!key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::digest;
+    use crate::TDigestView;
+
+    #[test]
+    fn test_layout() {
+        let bytes = digest().to_bytes();
+
+        let mut expected: Vec<u8> = b"TDGT".to_vec();
+        expected.extend_from_slice(&[1, INTEGRAL_WEIGHTS, 0, 100]);
+        for value in [4.0f64, 8.0, 1.0, 3.0] {
+            expected.extend_from_slice(&value.to_le_bytes());
+        }
+        expected.push(3);
+        // 1.0 is 0xbff0_0000_0000_0000 as a key, 2.0 and 3.0 are 2^52 and 2^51 above.
+        expected.extend_from_slice(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0xf8, 0xbf, 0x01, 1]);
+        expected.extend_from_slice(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x08, 2]);
+        expected.extend_from_slice(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x04, 1]);
+        let checksum = crc32(&expected);
+        expected.extend_from_slice(&checksum.to_le_bytes());
+
+        assert_eq!(bytes, expected);
+        assert_eq!(TDigest::from_bytes(&bytes).unwrap(), digest());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let values: Vec<f64> = (1..=10_000).map(|v| f64::from(v) / 7.0 - 500.0).collect();
+        let t = TDigest::new_with_size(200).with_scale(Scale::K2).merge_unsorted(values);
+
+        let decoded = TDigest::from_bytes(&t.to_bytes()).unwrap();
+        assert_eq!(decoded, t);
+
+        let weighted = TDigest::new_with_size(50).merge_weighted_unsorted(vec![(1.0, 0.5), (-2.0, 1.25)]);
+        assert_eq!(TDigest::from_bytes(&weighted.to_bytes()).unwrap(), weighted);
+
+        let decoded = TDigest::from_bytes(&t.to_bytes_with_precision(Precision::F32)).unwrap();
+        assert_eq!(decoded.count(), t.count());
+        assert_eq!(decoded.scale(), Scale::K2);
+        let expected = t.estimate_quantile(0.5);
+        let percentage: f64 = (decoded.estimate_quantile(0.5) - expected).abs() / expected.abs();
+        assert!(percentage < 1e-6);
+
+        let empty = TDigest::new_with_size(100);
+        assert_eq!(TDigest::from_bytes(&empty.to_bytes()).unwrap(), empty);
+    }
+
+    #[test]
+    fn test_f32_is_smaller() {
+        let t = TDigest::new_with_size(100).merge_unsorted((1..=10_000).map(|v| f64::from(v).sqrt()).collect());
+        assert!(t.to_bytes_with_precision(Precision::F32).len() < t.to_bytes().len());
+    }
+
+    #[test]
+    fn test_rejects_corrupt_input() {
+        let bytes = digest().to_bytes();
+
+        for len in 0..bytes.len() {
+            assert!(TDigest::from_bytes(&bytes[..len]).is_err());
+        }
+        for i in 0..bytes.len() {
+            let mut corrupt = bytes.clone();
+            corrupt[i] ^= 0x10;
+            assert!(TDigest::from_bytes(&corrupt).is_err());
+        }
+
+        let with_checksum = |mut body: Vec<u8>| {
+            let checksum = crc32(&body);
+            body.extend_from_slice(&checksum.to_le_bytes());
+            body
+        };
+        let body = &bytes[..bytes.len() - 4];
+
+        let mut version = body.to_vec();
+        version[4] = 2;
+        assert_eq!(
+            TDigest::from_bytes(&with_checksum(version)),
+            Err(TDigestError::InvalidEncoding("unsupported version"))
+        );
+
+        let mut huge = digest();
+        huge.max_size = MAX_DECODED_SIZE + 1;
+        assert_eq!(
+            TDigest::from_bytes(&huge.to_bytes()),
+            Err(TDigestError::InvalidEncoding("max_size out of range"))
+        );
+        assert!(TDigestView::new(&huge.to_bytes()).is_err());
+
+        let trailing = [body, &[0]].concat();
+        assert_eq!(
+            TDigest::from_bytes(&with_checksum(trailing)),
+            Err(TDigestError::InvalidEncoding("trailing bytes"))
+        );
+
+        let mut zero_weight = body.to_vec();
+        let last = zero_weight.len() - 1;
+        zero_weight[last] = 0;
+        assert_eq!(
+            TDigest::from_bytes(&with_checksum(zero_weight)),
+            Err(TDigestError::InvalidWeight(0.0))
+        );
+    }
+}
@@ -0,0 +1,51 @@
+use std::fmt;
+
+/// Errors reported by the fallible operations on a `TDigest`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TDigestError {
+    /// A NaN or infinite value was refused by `NonFinitePolicy::Reject`, or found in a digest.
+    NonFiniteValue(f64),
+    /// A weight that is not a positive finite number.
+    InvalidWeight(f64),
+    /// `max_size` must be at least 1.
+    InvalidMaxSize(usize),
+    /// The centroids are not sorted by mean.
+    UnsortedCentroids,
+    /// The count does not match the total weight of the centroids.
+    CountMismatch { count: f64, weight: f64 },
+    /// `min` and `max` do not bound the centroids.
+    InvalidBounds { min: f64, max: f64 },
+    /// The digest holds no value.
+    Empty,
+    /// A quantile outside of `[0, 1]`.
+    InvalidQuantile(f64),
+    /// Bytes or text that cannot be decoded into a digest.
+    InvalidEncoding(&'static str),
+    /// An argument outside of its documented range.
+    InvalidArgument(&'static str),
+}
+
+impl fmt::Display for TDigestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TDigestError::NonFiniteValue(value) => write!(f, "non-finite value {}", value),
+            TDigestError::InvalidWeight(weight) => {
+                write!(f, "invalid weight {}, expected a positive finite number", weight)
+            }
+            TDigestError::InvalidMaxSize(max_size) => write!(f, "invalid max_size {}, expected at least 1", max_size),
+            TDigestError::UnsortedCentroids => write!(f, "centroids are not sorted by mean"),
+            TDigestError::CountMismatch { count, weight } => {
+                write!(f, "count {} does not match the centroid weights {}", count, weight)
+            }
+            TDigestError::InvalidBounds { min, max } => {
+                write!(f, "min {} and max {} do not bound the centroids", min, max)
+            }
+            TDigestError::Empty => write!(f, "empty digest"),
+            TDigestError::InvalidQuantile(q) => write!(f, "invalid quantile {}, expected a value in [0, 1]", q),
+            TDigestError::InvalidEncoding(reason) => write!(f, "invalid encoding: {}", reason),
+            TDigestError::InvalidArgument(reason) => write!(f, "invalid argument: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for TDigestError {}
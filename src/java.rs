@@ -0,0 +1,245 @@
+//! Byte encodings of the reference Java implementation's `MergingDigest`.
+//!
+//! `to_bytes` and `to_small_bytes` produce the layouts of `asBytes` and `asSmallBytes`,
+//! `from_bytes` reads either of them back. Both are big-endian:
+//!
+//! ```text
+//! verbose: i32 1, f64 min, f64 max, f64 compression, i32 n, n * (f64 weight, f64 mean)
+//! small:   i32 2, f64 min, f64 max, f32 compression, i16 size, i16 buffer size, i16 n,
+//!          n * (f32 weight, f32 mean)
+//! ```
+//!
+//! The compression maps to `max_size`, and must be in `[1, MAX_DECODED_SIZE]` on decoding. The
+//! layouts store no sum, it is recomputed from the centroids on decoding.
+
+use crate::bytes::Reader;
+use crate::{Centroid, TDigest, TDigestError, MAX_DECODED_SIZE};
+
+const VERBOSE_ENCODING: i32 = 1;
+const SMALL_ENCODING: i32 = 2;
+
+/// Encodes `digest` like `MergingDigest.asBytes`.
+pub fn to_bytes(digest: &TDigest) -> Vec<u8> {
+    let digest = digest.merged();
+    let (min, max) = bounds(&digest);
+
+    let mut bytes: Vec<u8> = Vec::with_capacity(32 + 16 * digest.centroids.len());
+    bytes.extend_from_slice(&VERBOSE_ENCODING.to_be_bytes());
+    bytes.extend_from_slice(&min.to_be_bytes());
+    bytes.extend_from_slice(&max.to_be_bytes());
+    bytes.extend_from_slice(&(digest.max_size() as f64).to_be_bytes());
+    bytes.extend_from_slice(&(digest.centroids.len() as i32).to_be_bytes());
+    for centroid in digest.centroids.iter() {
+        bytes.extend_from_slice(&centroid.weight().to_be_bytes());
+        bytes.extend_from_slice(&centroid.mean().to_be_bytes());
+    }
+
+    bytes
+}
+
+/// Encodes `digest` like `MergingDigest.asSmallBytes`, with single precision centroids.
+///
+/// Fails if the digest has more centroids than the layout can count.
+pub fn to_small_bytes(digest: &TDigest) -> Result<Vec<u8>, TDigestError> {
+    let digest = digest.merged();
+    let (min, max) = bounds(&digest);
+
+    // Recent releases size the centroid array to `2 * ceil(compression)` plus 10 and the buffer to
+    // five times that. `fromBytes` allocates them from these fields, so they must hold `n`.
+    let n = digest.centroids.len();
+    let size = std::cmp::max(n, digest.max_size().saturating_mul(2).saturating_add(10));
+    let buffer_size = size.saturating_mul(5);
+    if buffer_size > i16::MAX as usize {
+        return Err(TDigestError::InvalidEncoding(
+            "too many centroids for the small encoding",
+        ));
+    }
+
+    let mut bytes: Vec<u8> = Vec::with_capacity(30 + 8 * n);
+    bytes.extend_from_slice(&SMALL_ENCODING.to_be_bytes());
+    bytes.extend_from_slice(&min.to_be_bytes());
+    bytes.extend_from_slice(&max.to_be_bytes());
+    bytes.extend_from_slice(&(digest.max_size() as f32).to_be_bytes());
+    bytes.extend_from_slice(&(size as i16).to_be_bytes());
+    bytes.extend_from_slice(&(buffer_size as i16).to_be_bytes());
+    bytes.extend_from_slice(&(n as i16).to_be_bytes());
+    for centroid in digest.centroids.iter() {
+        bytes.extend_from_slice(&(centroid.weight() as f32).to_be_bytes());
+        bytes.extend_from_slice(&(centroid.mean() as f32).to_be_bytes());
+    }
+
+    Ok(bytes)
+}
+
+/// Decodes either encoding of `MergingDigest`, failing on anything else or on trailing bytes.
+pub fn from_bytes(bytes: &[u8]) -> Result<TDigest, TDigestError> {
+    let mut reader = Reader::new(bytes);
+
+    let encoding = i32::from_be_bytes(reader.array()?);
+    let min = f64::from_be_bytes(reader.array()?);
+    let max = f64::from_be_bytes(reader.array()?);
+
+    let (compression, n, small) = match encoding {
+        VERBOSE_ENCODING => {
+            let compression = f64::from_be_bytes(reader.array()?);
+            let n = i32::from_be_bytes(reader.array()?);
+            (compression, i64::from(n), false)
+        }
+        SMALL_ENCODING => {
+            let compression = f64::from(f32::from_be_bytes(reader.array()?));
+            let _size = i16::from_be_bytes(reader.array()?);
+            let _buffer_size = i16::from_be_bytes(reader.array()?);
+            let n = i16::from_be_bytes(reader.array()?);
+            (compression, i64::from(n), true)
+        }
+        _ => return Err(TDigestError::InvalidEncoding("unknown MergingDigest encoding")),
+    };
+
+    if !(compression >= 1.0 && compression <= MAX_DECODED_SIZE as f64) {
+        return Err(TDigestError::InvalidEncoding("invalid compression"));
+    }
+    if n < 0 {
+        return Err(TDigestError::InvalidEncoding("negative centroid count"));
+    }
+
+    let centroid_len = if small { 8 } else { 16 };
+    let centroids_bytes = reader.take((n as usize).saturating_mul(centroid_len))?;
+    reader.finish()?;
+
+    let mut centroids: Vec<Centroid> = Vec::with_capacity(n as usize);
+    let mut count: f64 = 0.0;
+    let mut sum: f64 = 0.0;
+    let mut centroid_reader = Reader::new(centroids_bytes);
+    for _ in 0..n {
+        let (weight, mean) = if small {
+            let weight = f32::from_be_bytes(centroid_reader.array()?);
+            let mean = f32::from_be_bytes(centroid_reader.array()?);
+            (f64::from(weight), f64::from(mean))
+        } else {
+            let weight = f64::from_be_bytes(centroid_reader.array()?);
+            let mean = f64::from_be_bytes(centroid_reader.array()?);
+            (weight, mean)
+        };
+
+        count += weight;
+        sum += weight * mean;
+        centroids.push(Centroid::new(mean, weight));
+    }
+
+    let max_size = compression.round() as usize;
+    if centroids.is_empty() {
+        return TDigest::try_new(centroids, 0.0, 0.0, f64::NAN, f64::NAN, max_size);
+    }
+
+    // Single precision means can round past the double precision bounds.
+    let (min, max) = if small {
+        (
+            min.min(centroids[0].mean()),
+            max.max(centroids[centroids.len() - 1].mean()),
+        )
+    } else {
+        (min, max)
+    };
+
+    TDigest::try_new(centroids, sum, count, max, min, max_size)
+}
+
+/// Java starts from infinite bounds and keeps them while the digest is empty.
+fn bounds(digest: &TDigest) -> (f64, f64) {
+    if digest.is_empty() {
+        (f64::INFINITY, f64::NEG_INFINITY)
+    } else {
+        (digest.min(), digest.max())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::{digest, hex};
+
+    // Laid out by hand following `MergingDigest.asBytes` and `asSmallBytes` for a digest of
+    // compression 100 holding 1, 2, 2 and 3, with the two 2s in one centroid.
+    const VERBOSE: &str = concat!(
+        "00000001",
+        "3ff0000000000000",
+        "4008000000000000",
+        "4059000000000000",
+        "00000003",
+        "3ff0000000000000",
+        "3ff0000000000000",
+        "4000000000000000",
+        "4000000000000000",
+        "3ff0000000000000",
+        "4008000000000000",
+    );
+
+    const SMALL: &str = concat!(
+        "00000002",
+        "3ff0000000000000",
+        "4008000000000000",
+        "42c80000",
+        "00d2",
+        "041a",
+        "0003",
+        "3f800000",
+        "3f800000",
+        "40000000",
+        "40000000",
+        "3f800000",
+        "40400000",
+    );
+
+    #[test]
+    fn test_golden_vectors() {
+        assert_eq!(to_bytes(&digest()), hex(VERBOSE));
+        assert_eq!(to_small_bytes(&digest()).unwrap(), hex(SMALL));
+
+        assert_eq!(from_bytes(&hex(VERBOSE)).unwrap(), digest());
+        assert_eq!(from_bytes(&hex(SMALL)).unwrap(), digest());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let t = TDigest::new_with_size(200).merge_unsorted((1..=10_000).map(|v| f64::from(v) / 7.0).collect());
+
+        let decoded = from_bytes(&to_bytes(&t)).unwrap();
+        assert_eq!(decoded.centroids(), t.centroids());
+        assert_eq!(decoded.max_size(), 200);
+        assert_eq!(decoded.estimate_quantile(0.99), t.estimate_quantile(0.99));
+
+        let decoded = from_bytes(&to_small_bytes(&t).unwrap()).unwrap();
+        assert_eq!(decoded.count(), t.count());
+        let percentage: f64 =
+            (decoded.estimate_quantile(0.5) - t.estimate_quantile(0.5)).abs() / t.estimate_quantile(0.5);
+        assert!(percentage < 1e-6);
+
+        let empty = from_bytes(&to_bytes(&TDigest::new_with_size(100))).unwrap();
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn test_rejects_corrupt_input() {
+        let bytes = hex(VERBOSE);
+        assert!(from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(from_bytes(&[bytes.as_slice(), &[0]].concat()).is_err());
+
+        let mut unknown = bytes.clone();
+        unknown[3] = 3;
+        assert_eq!(
+            from_bytes(&unknown),
+            Err(TDigestError::InvalidEncoding("unknown MergingDigest encoding"))
+        );
+
+        let mut huge = bytes.clone();
+        huge[20..28].copy_from_slice(&1e300f64.to_be_bytes());
+        assert_eq!(
+            from_bytes(&huge),
+            Err(TDigestError::InvalidEncoding("invalid compression"))
+        );
+
+        let mut unsorted = bytes;
+        unsorted[40..48].copy_from_slice(&5.0f64.to_be_bytes());
+        assert_eq!(from_bytes(&unsorted), Err(TDigestError::UnsortedCentroids));
+    }
+}
@@ -28,12 +28,53 @@
 //! assert!(percentage < 0.01);
 //! ```
 
+#[cfg(feature = "use_arrow")]
+pub mod arrow;
+mod bytes;
+pub mod clickhouse;
+pub mod datasketches;
+mod encoding;
+mod error;
+pub mod java;
+#[cfg(feature = "use_metrics")]
+pub mod metrics;
+pub mod postgres;
+pub mod prometheus;
+pub mod redis;
+mod scale;
+#[cfg(feature = "use_serde")]
+mod serde_impl;
+#[cfg(feature = "use_sqlite")]
+pub mod sqlite;
+#[cfg(any(feature = "use_metrics", feature = "use_tower", feature = "use_tracing"))]
+mod sync;
+#[cfg(test)]
+mod test_helpers;
+#[cfg(feature = "use_tower")]
+pub mod tower;
+#[cfg(feature = "use_tracing")]
+pub mod tracing;
+mod view;
+
+pub use crate::encoding::Precision;
+pub use crate::error::TDigestError;
+pub use crate::scale::{Quadratic, Scale, ScaleFunction, K0, K1, K2, K3};
+pub use crate::view::TDigestView;
+
 use ordered_float::OrderedFloat;
+use std::borrow::Cow;
 use std::cmp::Ordering;
 
 #[cfg(feature = "use_serde")]
 use serde::{Deserialize, Serialize};
 
+/// Largest `max_size` accepted by the decoders, so that untrusted input cannot size an
+/// allocation.
+pub const MAX_DECODED_SIZE: usize = 100_000;
+
+/// Most values the insertion buffer holds before they are folded into the centroids.
+const MAX_BUFFER_CAPACITY: usize = 1 << 16;
+
 /// Centroid implementation to the cluster mentioned in the paper.
 #[derive(Debug, PartialEq, Eq, Clone)]
 #[cfg_attr(feature = "use_serde", derive(Serialize, Deserialize))]
@@ -93,9 +134,50 @@ impl Default for Centroid {
     }
 }
 
+/// What to do with NaN and infinite values when they are added to a `TDigest`.
+///
+/// Samples with a weight that is not a positive finite number are handled like NaN.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[cfg_attr(feature = "use_serde", derive(Serialize, Deserialize))]
+pub enum NonFinitePolicy {
+    /// Refuse the whole batch: the `try_*` methods return `TDigestError`, the other ones panic.
+    Reject,
+    /// Drop the sample and count it as rejected.
+    #[default]
+    Skip,
+    /// Clamp infinities to the smallest and largest finite values seen so far, including the rest
+    /// of the batch. NaN, and infinities coming before any finite value, are dropped and counted
+    /// as rejected.
+    Clamp,
+}
+
+impl NonFinitePolicy {
+    /// Returns the sample to record in place of `(value, weight)`, or `None` if it must be dropped.
+    ///
+    /// `bounds` are the values infinities are clamped to, see `TDigest::clamp_bounds`.
+    fn apply(self, value: f64, weight: f64, bounds: Option<(f64, f64)>) -> Option<Centroid> {
+        if !(weight > 0.0 && weight.is_finite()) {
+            return None;
+        }
+
+        if value.is_finite() {
+            Some(Centroid::new(value, weight))
+        } else if self == NonFinitePolicy::Clamp && !value.is_nan() {
+            let (min, max) = bounds?;
+            Some(Centroid::new(if value > 0.0 { max } else { min }, weight))
+        } else {
+            None
+        }
+    }
+}
+
 /// T-Digest to be operated on.
+///
+/// With `use_serde` it serializes as a versioned map, with `null` bounds while it is empty:
+/// `{"version":1,"max_size":100,"scale":"Quadratic","count":2.0,"sum":3.0,"min":1.0,"max":2.0,
+/// "centroids":[[1.0,1.0],[2.0,1.0]]}`. The non-finite policy and the rejected count are not
+/// serialized. Maps without a `version`, as serialized by 0.2 and before, are still read.
 #[derive(Debug, PartialEq, Eq, Clone)]
-#[cfg_attr(feature = "use_serde", derive(Serialize, Deserialize))]
 pub struct TDigest {
     centroids: Vec<Centroid>,
     max_size: usize,
@@ -103,6 +185,11 @@ pub struct TDigest {
     count: OrderedFloat<f64>,
     max: OrderedFloat<f64>,
     min: OrderedFloat<f64>,
+    scale: Scale,
+    policy: NonFinitePolicy,
+    rejected: u64,
+    buffer: Vec<OrderedFloat<f64>>,
+    scratch: Vec<Centroid>,
 }
 
 impl TDigest {
@@ -112,11 +199,19 @@ impl TDigest {
             max_size,
             sum: OrderedFloat::from(0.0),
             count: OrderedFloat::from(0.0),
-            max: OrderedFloat::from(std::f64::NAN),
-            min: OrderedFloat::from(std::f64::NAN),
+            max: OrderedFloat::from(f64::NAN),
+            min: OrderedFloat::from(f64::NAN),
+            scale: Scale::default(),
+            policy: NonFinitePolicy::default(),
+            rejected: 0,
+            buffer: Vec::new(),
+            scratch: Vec::new(),
         }
     }
 
+    /// Builds a digest from its parts without checking them, see `try_new`.
+    ///
+    /// When there are more than `max_size` centroids they are compressed using `max_size`.
     pub fn new(centroids: Vec<Centroid>, sum: f64, count: f64, max: f64, min: f64, max_size: usize) -> Self {
         if centroids.len() <= max_size {
             TDigest {
@@ -126,11 +221,16 @@ impl TDigest {
                 count: OrderedFloat::from(count),
                 max: OrderedFloat::from(max),
                 min: OrderedFloat::from(min),
+                scale: Scale::default(),
+                policy: NonFinitePolicy::default(),
+                rejected: 0,
+                buffer: Vec::new(),
+                scratch: Vec::new(),
             }
         } else {
             let sz = centroids.len();
             let digests: Vec<TDigest> = vec![
-                TDigest::new_with_size(100),
+                TDigest::new_with_size(max_size),
                 TDigest::new(centroids, sum, count, max, min, sz),
             ];
 
@@ -138,10 +238,74 @@ impl TDigest {
         }
     }
 
+    /// Same as `new`, but checks that the parts describe a valid digest: `max_size` is at
+    /// least 1, the centroids are sorted with finite means and positive finite weights,
+    /// `count` matches their total weight, `sum` is finite and `min`/`max` bound them. Without
+    /// centroids, `sum` must be 0 and `min`/`max` NaN.
+    pub fn try_new(
+        centroids: Vec<Centroid>,
+        sum: f64,
+        count: f64,
+        max: f64,
+        min: f64,
+        max_size: usize,
+    ) -> Result<Self, TDigestError> {
+        Self::check_parts(centroids.iter().cloned(), sum, count, max, min, max_size)?;
+        Ok(Self::new(centroids, sum, count, max, min, max_size))
+    }
+
+    /// Checks the parts of a digest as documented on `try_new`, without collecting the centroids.
+    fn check_parts<I: Iterator<Item = Centroid>>(
+        centroids: I,
+        sum: f64,
+        count: f64,
+        max: f64,
+        min: f64,
+        max_size: usize,
+    ) -> Result<(), TDigestError> {
+        if max_size == 0 {
+            return Err(TDigestError::InvalidMaxSize(max_size));
+        }
+
+        let mut weight: f64 = 0.0;
+        let mut bounds: Option<(f64, f64)> = None;
+        for centroid in centroids {
+            if !centroid.mean().is_finite() {
+                return Err(TDigestError::NonFiniteValue(centroid.mean()));
+            }
+            if !(centroid.weight() > 0.0 && centroid.weight().is_finite()) {
+                return Err(TDigestError::InvalidWeight(centroid.weight()));
+            }
+            bounds = match bounds {
+                Some((_, last)) if last > centroid.mean() => return Err(TDigestError::UnsortedCentroids),
+                Some((first, _)) => Some((first, centroid.mean())),
+                None => Some((centroid.mean(), centroid.mean())),
+            };
+            weight += centroid.weight();
+        }
+
+        if count.is_nan() || (count - weight).abs() > 1e-9 * weight.max(1.0) {
+            return Err(TDigestError::CountMismatch { count, weight });
+        }
+
+        if !sum.is_finite() {
+            return Err(TDigestError::NonFiniteValue(sum));
+        }
+
+        match bounds {
+            Some((first, last)) if !(min.is_finite() && max.is_finite() && min <= first && last <= max) => {
+                Err(TDigestError::InvalidBounds { min, max })
+            }
+            None if !(min.is_nan() && max.is_nan()) => Err(TDigestError::InvalidBounds { min, max }),
+            None if sum != 0.0 => Err(TDigestError::InvalidArgument("the sum of an empty digest must be 0")),
+            _ => Ok(()),
+        }
+    }
+
     #[inline]
     pub fn mean(&self) -> f64 {
-        let count_: f64 = self.count.into_inner();
-        let sum_: f64 = self.sum.into_inner();
+        let count_: f64 = self.count();
+        let sum_: f64 = self.sum();
 
         if count_ > 0.0 {
             sum_ / count_
@@ -152,33 +316,114 @@ impl TDigest {
 
     #[inline]
     pub fn sum(&self) -> f64 {
-        self.sum.into_inner()
+        self.buffer
+            .iter()
+            .fold(self.sum.into_inner(), |acc, v| acc + v.into_inner())
     }
 
     #[inline]
     pub fn count(&self) -> f64 {
-        self.count.into_inner()
+        self.count.into_inner() + self.buffer.len() as f64
     }
 
     #[inline]
     pub fn max(&self) -> f64 {
-        self.max.into_inner()
+        match self.buffer.iter().max() {
+            Some(&v) if self.count.into_inner() > 0.0 => std::cmp::max(self.max, v).into_inner(),
+            Some(&v) => v.into_inner(),
+            None => self.max.into_inner(),
+        }
     }
 
     #[inline]
     pub fn min(&self) -> f64 {
-        self.min.into_inner()
+        match self.buffer.iter().min() {
+            Some(&v) if self.count.into_inner() > 0.0 => std::cmp::min(self.min, v).into_inner(),
+            Some(&v) => v.into_inner(),
+            None => self.min.into_inner(),
+        }
+    }
+
+    /// The centroids, sorted by mean.
+    ///
+    /// Values still waiting in the insertion buffer are not included, `flush` first to see them.
+    #[inline]
+    pub fn centroids(&self) -> &[Centroid] {
+        &self.centroids
+    }
+
+    /// Iterates over the centroids along with the total weight of the centroids up to and
+    /// including each of them. Like `centroids`, ignores the insertion buffer.
+    pub fn cumulative_weights(&self) -> impl Iterator<Item = (&Centroid, f64)> + '_ {
+        self.centroids.iter().scan(0.0, |t: &mut f64, centroid| {
+            *t += centroid.weight();
+            Some((centroid, *t))
+        })
     }
 
     #[inline]
     pub fn is_empty(&self) -> bool {
-        self.centroids.is_empty()
+        self.centroids.is_empty() && self.buffer.is_empty()
+    }
+
+    /// Same as `mean`, but `None` for an empty digest.
+    pub fn checked_mean(&self) -> Option<f64> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self.mean())
+        }
+    }
+
+    /// Same as `max`, but `None` for an empty digest.
+    pub fn checked_max(&self) -> Option<f64> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self.max())
+        }
+    }
+
+    /// Same as `min`, but `None` for an empty digest.
+    pub fn checked_min(&self) -> Option<f64> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self.min())
+        }
     }
 
     #[inline]
     pub fn max_size(&self) -> usize {
         self.max_size
     }
+
+    #[inline]
+    pub fn scale(&self) -> Scale {
+        self.scale
+    }
+
+    #[inline]
+    pub fn non_finite_policy(&self) -> NonFinitePolicy {
+        self.policy
+    }
+
+    /// Number of samples dropped so far by the `NonFinitePolicy`.
+    #[inline]
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+
+    /// Sets the scale function used when compressing, `Scale::Quadratic` by default.
+    pub fn with_scale(mut self, scale: Scale) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    pub fn with_non_finite_policy(mut self, policy: NonFinitePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
 }
 
 impl Default for TDigest {
@@ -188,23 +433,43 @@ impl Default for TDigest {
             max_size: 100,
             sum: OrderedFloat::from(0.0),
             count: OrderedFloat::from(0.0),
-            max: OrderedFloat::from(std::f64::NAN),
-            min: OrderedFloat::from(std::f64::NAN),
+            max: OrderedFloat::from(f64::NAN),
+            min: OrderedFloat::from(f64::NAN),
+            scale: Scale::default(),
+            policy: NonFinitePolicy::default(),
+            rejected: 0,
+            buffer: Vec::new(),
+            scratch: Vec::new(),
         }
     }
 }
 
-impl TDigest {
-    fn k_to_q(k: f64, d: f64) -> f64 {
-        let k_div_d = k / d;
-        if k_div_d >= 0.5 {
-            let base = 1.0 - k_div_d;
-            1.0 - 2.0 * base * base
-        } else {
-            2.0 * k_div_d * k_div_d
+/// Values are buffered and merged in chunks of the insertion buffer size, so the
+/// iterator is never materialized. Panics like `insert` under `NonFinitePolicy::Reject`.
+impl Extend<f64> for TDigest {
+    fn extend<I: IntoIterator<Item = f64>>(&mut self, iter: I) {
+        for value in iter {
+            self.insert(value);
         }
     }
+}
+
+impl<'a> Extend<&'a f64> for TDigest {
+    fn extend<I: IntoIterator<Item = &'a f64>>(&mut self, iter: I) {
+        self.extend(iter.into_iter().copied());
+    }
+}
 
+impl FromIterator<f64> for TDigest {
+    fn from_iter<I: IntoIterator<Item = f64>>(iter: I) -> Self {
+        let mut digest = TDigest::default();
+        digest.extend(iter);
+        digest.flush();
+        digest
+    }
+}
+
+impl TDigest {
     fn clamp(v: f64, lo: f64, hi: f64) -> f64 {
         if v > hi {
             hi
@@ -215,51 +480,319 @@ impl TDigest {
         }
     }
 
+    fn buffer_capacity(&self) -> usize {
+        std::cmp::max(self.max_size, 1)
+            .saturating_mul(5)
+            .min(MAX_BUFFER_CAPACITY)
+    }
+
+    /// Returns this digest with the insertion buffer folded into the centroids.
+    fn merged(&self) -> Cow<'_, TDigest> {
+        if self.buffer.is_empty() {
+            Cow::Borrowed(self)
+        } else {
+            let mut digest = self.clone();
+            digest.flush();
+            Cow::Owned(digest)
+        }
+    }
+
+    /// Finite bounds for `NonFinitePolicy::Clamp`: the smallest and largest finite values of this
+    /// digest and of `values`. `None` under other policies or when `values` has no infinity.
+    fn clamp_bounds<I>(&self, values: I) -> Option<(f64, f64)>
+    where
+        I: Iterator<Item = (f64, f64)> + Clone,
+    {
+        if self.policy != NonFinitePolicy::Clamp || !values.clone().any(|(v, _)| v.is_infinite()) {
+            return None;
+        }
+
+        let current = if self.is_empty() {
+            None
+        } else {
+            Some((self.min(), self.max()))
+        };
+        values.filter(|&(v, w)| v.is_finite() && w > 0.0 && w.is_finite()).fold(
+            current,
+            |bounds, (v, _)| match bounds {
+                Some((min, max)) => Some((min.min(v), max.max(v))),
+                None => Some((v, v)),
+            },
+        )
+    }
+
+    /// Counts the samples of `values` dropped by the policy, failing on the first one under
+    /// `NonFinitePolicy::Reject`.
+    fn check_samples<I>(&self, values: I, bounds: Option<(f64, f64)>) -> Result<u64, TDigestError>
+    where
+        I: Iterator<Item = (f64, f64)>,
+    {
+        let mut rejected: u64 = 0;
+        for (value, weight) in values {
+            if self.policy.apply(value, weight, bounds).is_none() {
+                if self.policy == NonFinitePolicy::Reject {
+                    return Err(if weight > 0.0 && weight.is_finite() {
+                        TDigestError::NonFiniteValue(value)
+                    } else {
+                        TDigestError::InvalidWeight(weight)
+                    });
+                }
+                rejected += 1;
+            }
+        }
+
+        Ok(rejected)
+    }
+
+    /// Adds a single value, buffering it until the insertion buffer is full.
+    ///
+    /// # Panics
+    ///
+    /// If `value` is refused by `NonFinitePolicy::Reject`, see `try_insert`.
+    pub fn insert(&mut self, value: f64) {
+        self.try_insert(value).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    pub fn try_insert(&mut self, value: f64) -> Result<(), TDigestError> {
+        self.try_insert_many(&[value])
+    }
+
+    /// Adds every value of `values`, see `insert`.
+    pub fn insert_many(&mut self, values: &[f64]) {
+        self.try_insert_many(values).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Adds every value of `values`, or none of them if one is refused by the policy.
+    pub fn try_insert_many(&mut self, values: &[f64]) -> Result<(), TDigestError> {
+        self.try_insert_values(values.iter().cloned())
+    }
+
+    /// Same as `try_insert_many`, for values that can be iterated over more than once.
+    fn try_insert_values<I>(&mut self, values: I) -> Result<(), TDigestError>
+    where
+        I: Iterator<Item = f64> + Clone,
+    {
+        let bounds = self.clamp_bounds(values.clone().map(|v| (v, 1.0)));
+        self.rejected += self.check_samples(values.clone().map(|v| (v, 1.0)), bounds)?;
+
+        for value in values {
+            if let Some(centroid) = self.policy.apply(value, 1.0, bounds) {
+                self.buffer.push(centroid.mean);
+                if self.buffer.len() >= self.buffer_capacity() {
+                    self.flush();
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Folds the values waiting in the insertion buffer into the centroids.
+    ///
+    /// Queries flush transparently, calling this beforehand only saves them the work.
+    pub fn flush(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+
+        let mut buffer = std::mem::take(&mut self.buffer);
+        buffer.sort_unstable();
+        self.merge_sorted_centroids_in_place(buffer.iter().map(|v| Centroid::new(v.into_inner(), 1.0)));
+
+        buffer.clear();
+        self.buffer = buffer;
+    }
+
+    /// Merges `sorted_values` into this digest in place, without copying them.
+    pub fn merge_sorted_slice(&mut self, sorted_values: &[f64]) {
+        self.try_merge_sorted_slice(sorted_values)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    pub fn try_merge_sorted_slice(&mut self, sorted_values: &[f64]) -> Result<(), TDigestError> {
+        let bounds = self.clamp_bounds(sorted_values.iter().map(|&v| (v, 1.0)));
+        self.rejected += self.check_samples(sorted_values.iter().map(|&v| (v, 1.0)), bounds)?;
+
+        let policy = self.policy;
+        self.merge_sorted_centroids_in_place(sorted_values.iter().filter_map(move |&v| policy.apply(v, 1.0, bounds)));
+        Ok(())
+    }
+
+    /// Sorts `unsorted_values` in place and merges them into this digest.
+    pub fn merge_unsorted_slice(&mut self, unsorted_values: &mut [f64]) {
+        self.try_merge_unsorted_slice(unsorted_values)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    pub fn try_merge_unsorted_slice(&mut self, unsorted_values: &mut [f64]) -> Result<(), TDigestError> {
+        unsorted_values.sort_unstable_by_key(|&v| OrderedFloat::from(v));
+        self.try_merge_sorted_slice(unsorted_values)
+    }
+
     pub fn merge_unsorted(&self, unsorted_values: Vec<f64>) -> TDigest {
+        self.try_merge_unsorted(unsorted_values)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    pub fn try_merge_unsorted(&self, unsorted_values: Vec<f64>) -> Result<TDigest, TDigestError> {
         let mut sorted_values: Vec<OrderedFloat<f64>> = unsorted_values.into_iter().map(OrderedFloat::from).collect();
         sorted_values.sort();
         let sorted_values = sorted_values.into_iter().map(|f| f.into_inner()).collect();
 
-        self.merge_sorted(sorted_values)
+        self.try_merge_sorted(sorted_values)
     }
 
+    /// # Panics
+    ///
+    /// If one of the values is refused by `NonFinitePolicy::Reject`, see `try_merge_sorted`.
     pub fn merge_sorted(&self, sorted_values: Vec<f64>) -> TDigest {
-        if sorted_values.is_empty() {
-            return self.clone();
+        self.try_merge_sorted(sorted_values)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    pub fn try_merge_sorted(&self, sorted_values: Vec<f64>) -> Result<TDigest, TDigestError> {
+        self.try_merge_sorted_samples(sorted_values.iter().map(|&v| (v, 1.0)))
+    }
+
+    /// Merges `(value, weight)` pairs, each standing for `weight` observations of `value`.
+    ///
+    /// Pairs whose weight is not a positive finite number are handled by the `NonFinitePolicy`.
+    pub fn merge_weighted_unsorted(&self, unsorted_values: Vec<(f64, f64)>) -> TDigest {
+        self.try_merge_weighted_unsorted(unsorted_values)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    pub fn try_merge_weighted_unsorted(&self, unsorted_values: Vec<(f64, f64)>) -> Result<TDigest, TDigestError> {
+        let mut sorted_values = unsorted_values;
+        sorted_values.sort_by_key(|&(v, _)| OrderedFloat::from(v));
+
+        self.try_merge_weighted_sorted(sorted_values)
+    }
+
+    /// Same as `merge_weighted_unsorted`, for pairs already sorted by value.
+    pub fn merge_weighted_sorted(&self, sorted_values: Vec<(f64, f64)>) -> TDigest {
+        self.try_merge_weighted_sorted(sorted_values)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    pub fn try_merge_weighted_sorted(&self, sorted_values: Vec<(f64, f64)>) -> Result<TDigest, TDigestError> {
+        self.try_merge_sorted_samples(sorted_values.iter().cloned())
+    }
+
+    fn try_merge_sorted_samples<I>(&self, sorted_values: I) -> Result<TDigest, TDigestError>
+    where
+        I: Iterator<Item = (f64, f64)> + Clone,
+    {
+        let bounds = self.clamp_bounds(sorted_values.clone());
+        let rejected = self.check_samples(sorted_values.clone(), bounds)?;
+
+        let policy = self.policy;
+        let mut result =
+            self.merge_sorted_centroids(sorted_values.filter_map(move |(v, w)| policy.apply(v, w, bounds)));
+        result.rejected += rejected;
+        Ok(result)
+    }
+
+    fn merge_sorted_centroids<I>(&self, sorted_values: I) -> TDigest
+    where
+        I: Iterator<Item = Centroid> + Clone,
+    {
+        if !self.buffer.is_empty() {
+            return self.merged().merge_sorted_centroids(sorted_values);
+        }
+
+        let mut compressed: Vec<Centroid> = Vec::new();
+        match self.compress(sorted_values, &mut compressed) {
+            Some((sum, count, max, min)) => {
+                compressed.shrink_to_fit();
+
+                let mut result = TDigest::new_with_size(self.max_size());
+                result.scale = self.scale;
+                result.policy = self.policy;
+                result.rejected = self.rejected;
+                result.centroids = compressed;
+                result.sum = OrderedFloat::from(sum);
+                result.count = OrderedFloat::from(count);
+                result.max = OrderedFloat::from(max);
+                result.min = OrderedFloat::from(min);
+                result
+            }
+            None => self.clone(),
         }
+    }
 
-        let mut result = TDigest::new_with_size(self.max_size());
-        result.count = OrderedFloat::from(self.count() + (sorted_values.len() as f64));
+    /// Same as `merge_sorted_centroids` but updates `self`, reusing `scratch` for the
+    /// compressed centroids so that no allocation happens once the buffers are warmed up.
+    fn merge_sorted_centroids_in_place<I>(&mut self, sorted_values: I)
+    where
+        I: Iterator<Item = Centroid> + Clone,
+    {
+        self.flush();
+
+        let mut compressed = std::mem::take(&mut self.scratch);
+        if let Some((sum, count, max, min)) = self.compress(sorted_values, &mut compressed) {
+            std::mem::swap(&mut self.centroids, &mut compressed);
+            self.sum = OrderedFloat::from(sum);
+            self.count = OrderedFloat::from(count);
+            self.max = OrderedFloat::from(max);
+            self.min = OrderedFloat::from(min);
+        }
 
-        let maybe_min = OrderedFloat::from(*sorted_values.first().unwrap());
-        let maybe_max = OrderedFloat::from(*sorted_values.last().unwrap());
+        compressed.clear();
+        self.scratch = compressed;
+    }
 
-        if self.count() > 0.0 {
-            result.min = std::cmp::min(self.min, maybe_min);
-            result.max = std::cmp::max(self.max, maybe_max);
-        } else {
-            result.min = maybe_min;
-            result.max = maybe_max;
+    /// Merges the centroids with `sorted_values` into `compressed`, returning the resulting
+    /// `(sum, count, max, min)` or `None` if `sorted_values` is empty. Ignores the insertion buffer.
+    fn compress<I>(&self, sorted_values: I, compressed: &mut Vec<Centroid>) -> Option<(f64, f64, f64, f64)>
+    where
+        I: Iterator<Item = Centroid> + Clone,
+    {
+        let mut added_weight: f64 = 0.0;
+        let mut n_values: usize = 0;
+        let mut maybe_min: Option<OrderedFloat<f64>> = None;
+        let mut maybe_max: Option<OrderedFloat<f64>> = None;
+        for value in sorted_values.clone() {
+            added_weight += value.weight();
+            n_values += 1;
+            maybe_min = maybe_min.or(Some(value.mean));
+            maybe_max = Some(value.mean);
         }
 
-        let mut compressed: Vec<Centroid> = Vec::with_capacity(self.max_size);
+        let (maybe_min, maybe_max) = match (maybe_min, maybe_max) {
+            (Some(min), Some(max)) => (min, max),
+            _ => return None,
+        };
 
-        let mut k_limit: f64 = 1.0;
-        let mut q_limit_times_count: f64 = Self::k_to_q(k_limit, self.max_size as f64) * result.count.into_inner();
+        let count = OrderedFloat::from(self.count.into_inner() + added_weight);
+        let (max, min) = if self.count.into_inner() > 0.0 {
+            (std::cmp::max(self.max, maybe_max), std::cmp::min(self.min, maybe_min))
+        } else {
+            (maybe_max, maybe_min)
+        };
+
+        let mut sum: f64 = 0.0;
+        compressed.clear();
+        compressed.reserve(self.max_size.min(self.centroids.len() + n_values));
+
+        let d: f64 = self.max_size as f64;
+        let n: f64 = count.into_inner();
+        let mut k_limit: f64 = self.scale.k(0.0, d, n) + 1.0;
+        let mut q_limit_times_count: f64 = self.scale.q(k_limit, d, n) * n;
         k_limit += 1.0;
 
         let mut iter_centroids = self.centroids.iter().peekable();
-        let mut iter_sorted_values = sorted_values.iter().peekable();
+        let mut iter_sorted_values = sorted_values.peekable();
 
         let mut curr: Centroid = if let Some(c) = iter_centroids.peek() {
-            let curr = **iter_sorted_values.peek().unwrap();
+            let curr = iter_sorted_values.peek().unwrap().mean();
             if c.mean() < curr {
                 iter_centroids.next().unwrap().clone()
             } else {
-                Centroid::new(*iter_sorted_values.next().unwrap(), 1.0)
+                iter_sorted_values.next().unwrap()
             }
         } else {
-            Centroid::new(*iter_sorted_values.next().unwrap(), 1.0)
+            iter_sorted_values.next().unwrap()
         };
 
         let mut weight_so_far: f64 = curr.weight();
@@ -269,13 +802,13 @@ impl TDigest {
 
         while iter_centroids.peek().is_some() || iter_sorted_values.peek().is_some() {
             let next: Centroid = if let Some(c) = iter_centroids.peek() {
-                if iter_sorted_values.peek().is_none() || c.mean() < **iter_sorted_values.peek().unwrap() {
+                if iter_sorted_values.peek().is_none() || c.mean() < iter_sorted_values.peek().unwrap().mean() {
                     iter_centroids.next().unwrap().clone()
                 } else {
-                    Centroid::new(*iter_sorted_values.next().unwrap(), 1.0)
+                    iter_sorted_values.next().unwrap()
                 }
             } else {
-                Centroid::new(*iter_sorted_values.next().unwrap(), 1.0)
+                iter_sorted_values.next().unwrap()
             };
 
             let next_sum: f64 = next.mean() * next.weight();
@@ -285,27 +818,25 @@ impl TDigest {
                 sums_to_merge += next_sum;
                 weights_to_merge += next.weight();
             } else {
-                result.sum = OrderedFloat::from(result.sum.into_inner() + curr.add(sums_to_merge, weights_to_merge));
+                sum += curr.add(sums_to_merge, weights_to_merge);
                 sums_to_merge = 0.0;
                 weights_to_merge = 0.0;
 
                 compressed.push(curr.clone());
-                q_limit_times_count = Self::k_to_q(k_limit, self.max_size as f64) * result.count();
+                q_limit_times_count = self.scale.q(k_limit, d, n) * n;
                 k_limit += 1.0;
                 curr = next;
             }
         }
 
-        result.sum = OrderedFloat::from(result.sum.into_inner() + curr.add(sums_to_merge, weights_to_merge));
+        sum += curr.add(sums_to_merge, weights_to_merge);
         compressed.push(curr);
-        compressed.shrink_to_fit();
-        compressed.sort();
+        compressed.sort_unstable();
 
-        result.centroids = compressed;
-        result
+        Some((sum, count.into_inner(), max.into_inner(), min.into_inner()))
     }
 
-    fn external_merge(centroids: &mut Vec<Centroid>, first: usize, middle: usize, last: usize) {
+    fn external_merge(centroids: &mut [Centroid], first: usize, middle: usize, last: usize) {
         let mut result: Vec<Centroid> = Vec::with_capacity(centroids.len());
 
         let mut i = first;
@@ -345,24 +876,39 @@ impl TDigest {
         }
     }
 
-    // Merge multiple T-Digests
-    pub fn merge_digests(digests: Vec<TDigest>) -> TDigest {
+    // Merge multiple T-Digests, compressing with the max_size and scale of the first one
+    pub fn merge_digests(mut digests: Vec<TDigest>) -> TDigest {
+        for digest in digests.iter_mut() {
+            digest.flush();
+        }
+
         let n_centroids: usize = digests.iter().map(|d| d.centroids.len()).sum();
         if n_centroids == 0 {
-            return TDigest::default();
+            let mut result = match digests.first() {
+                Some(first) => TDigest::new_with_size(first.max_size)
+                    .with_scale(first.scale)
+                    .with_non_finite_policy(first.policy),
+                None => TDigest::default(),
+            };
+            result.rejected = digests.iter().map(|d| d.rejected).sum();
+            return result;
         }
 
         let max_size = digests.first().unwrap().max_size;
+        let scale = digests.first().unwrap().scale;
+        let policy = digests.first().unwrap().policy;
         let mut centroids: Vec<Centroid> = Vec::with_capacity(n_centroids);
         let mut starts: Vec<usize> = Vec::with_capacity(digests.len());
 
         let mut count: f64 = 0.0;
-        let mut min = OrderedFloat::from(std::f64::INFINITY);
-        let mut max = OrderedFloat::from(std::f64::NEG_INFINITY);
+        let mut rejected: u64 = 0;
+        let mut min = OrderedFloat::from(f64::INFINITY);
+        let mut max = OrderedFloat::from(f64::NEG_INFINITY);
 
         let mut start: usize = 0;
         for digest in digests.into_iter() {
             starts.push(start);
+            rejected += digest.rejected;
 
             let curr_count: f64 = digest.count();
             if curr_count > 0.0 {
@@ -397,10 +943,11 @@ impl TDigest {
         }
 
         let mut result = TDigest::new_with_size(max_size);
-        let mut compressed: Vec<Centroid> = Vec::with_capacity(max_size);
+        let mut compressed: Vec<Centroid> = Vec::with_capacity(max_size.min(n_centroids));
 
-        let mut k_limit: f64 = 1.0;
-        let mut q_limit_times_count: f64 = Self::k_to_q(k_limit, max_size as f64) * (count as f64);
+        let d: f64 = max_size as f64;
+        let mut k_limit: f64 = scale.k(0.0, d, count) + 1.0;
+        let mut q_limit_times_count: f64 = scale.q(k_limit, d, count) * count;
 
         let mut iter_centroids = centroids.iter_mut();
         let mut curr = iter_centroids.next().unwrap();
@@ -419,7 +966,7 @@ impl TDigest {
                 sums_to_merge = 0.0;
                 weights_to_merge = 0.0;
                 compressed.push(curr.clone());
-                q_limit_times_count = Self::k_to_q(k_limit, max_size as f64) * (count as f64);
+                q_limit_times_count = scale.q(k_limit, d, count) * count;
                 k_limit += 1.0;
                 curr = centroid;
             }
@@ -430,15 +977,36 @@ impl TDigest {
         compressed.shrink_to_fit();
         compressed.sort();
 
-        result.count = OrderedFloat::from(count as f64);
+        result.count = OrderedFloat::from(count);
         result.min = min;
         result.max = max;
+        result.scale = scale;
+        result.policy = policy;
+        result.rejected = rejected;
         result.centroids = compressed;
         result
     }
 
+    /// Same as `estimate_quantile`, but fails on an empty digest or a `q` outside of `[0, 1]`
+    /// instead of returning `0.0` or clamping `q`.
+    pub fn try_estimate_quantile(&self, q: f64) -> Result<f64, TDigestError> {
+        if !(0.0..=1.0).contains(&q) {
+            return Err(TDigestError::InvalidQuantile(q));
+        }
+
+        if self.is_empty() {
+            return Err(TDigestError::Empty);
+        }
+
+        Ok(self.estimate_quantile(q))
+    }
+
     /// To estimate the value located at `q` quantile
     pub fn estimate_quantile(&self, q: f64) -> f64 {
+        if !self.buffer.is_empty() {
+            return self.merged().estimate_quantile(q);
+        }
+
         if self.centroids.is_empty() {
             return 0.0;
         }
@@ -482,27 +1050,262 @@ impl TDigest {
             }
         }
 
+        self.interpolate_quantile(pos, t, rank)
+    }
+
+    /// To estimate the values located at each of the `qs` quantiles.
+    ///
+    /// The quantiles are answered in a single sweep over the centroids, from the front for
+    /// those up to `0.5` and from the back for the others, giving the same results as
+    /// calling `estimate_quantile` on each of them.
+    pub fn estimate_quantiles(&self, qs: &[f64]) -> Vec<f64> {
+        if !self.buffer.is_empty() {
+            return self.merged().estimate_quantiles(qs);
+        }
+
+        let mut result: Vec<f64> = vec![0.0; qs.len()];
+        if self.centroids.is_empty() {
+            return result;
+        }
+
+        let mut order: Vec<usize> = (0..qs.len()).collect();
+        order.sort_by_key(|&i| OrderedFloat::from(qs[i]));
+        let (forward, backward): (Vec<usize>, Vec<usize>) =
+            order.into_iter().partition(|&i| qs[i] <= 0.5 || qs[i].is_nan());
+
+        let count_: f64 = self.count.into_inner();
+
+        let mut k: usize = 0;
+        let mut t: f64 = 0.0;
+        for i in forward {
+            if qs[i] <= 0.0 {
+                result[i] = self.min();
+                continue;
+            }
+
+            let rank: f64 = qs[i] * count_;
+            while k < self.centroids.len() && (rank.is_nan() || rank >= t + self.centroids[k].weight()) {
+                t += self.centroids[k].weight();
+                k += 1;
+            }
+
+            let pos: usize = std::cmp::min(k, self.centroids.len() - 1);
+            result[i] = self.interpolate_quantile(pos, t, rank);
+        }
+
+        let mut remaining: usize = self.centroids.len();
+        let mut last: Option<usize> = None;
+        let mut t: f64 = count_;
+        for i in backward.into_iter().rev() {
+            if qs[i] >= 1.0 {
+                result[i] = self.max();
+                continue;
+            }
+
+            let rank: f64 = qs[i] * count_;
+            let pos: usize = loop {
+                match last {
+                    Some(k) if rank >= t => break k,
+                    _ if remaining == 0 => break 0,
+                    _ => {
+                        remaining -= 1;
+                        t -= self.centroids[remaining].weight();
+                        last = Some(remaining);
+                    }
+                }
+            };
+
+            result[i] = self.interpolate_quantile(pos, t, rank);
+        }
+
+        result
+    }
+
+    /// Interpolates the value at `rank` within the centroid at `pos`, `t` being the weight before it.
+    fn interpolate_quantile(&self, pos: usize, t: f64, rank: f64) -> f64 {
+        let prev: Option<f64> = pos.checked_sub(1).map(|k| self.centroids[k].mean());
+        let next: Option<f64> = self.centroids.get(pos + 1).map(|c| c.mean());
+        Self::interpolate(
+            prev,
+            &self.centroids[pos],
+            next,
+            t,
+            rank,
+            (self.min.into_inner(), self.max.into_inner()),
+        )
+    }
+
+    /// Interpolates the value at `rank` within `centroid`, given the means of its neighbours,
+    /// the weight `t` before it and the bounds of the digest.
+    fn interpolate(
+        prev: Option<f64>,
+        centroid: &Centroid,
+        next: Option<f64>,
+        t: f64,
+        rank: f64,
+        (mut min, mut max): (f64, f64),
+    ) -> f64 {
         let mut delta = 0.0;
-        let mut min: f64 = self.min.into_inner();
-        let mut max: f64 = self.max.into_inner();
-
-        if self.centroids.len() > 1 {
-            if pos == 0 {
-                delta = self.centroids[pos + 1].mean() - self.centroids[pos].mean();
-                max = self.centroids[pos + 1].mean();
-            } else if pos == (self.centroids.len() - 1) {
-                delta = self.centroids[pos].mean() - self.centroids[pos - 1].mean();
-                min = self.centroids[pos - 1].mean();
-            } else {
-                delta = (self.centroids[pos + 1].mean() - self.centroids[pos - 1].mean()) / 2.0;
-                min = self.centroids[pos - 1].mean();
-                max = self.centroids[pos + 1].mean();
+
+        match (prev, next) {
+            (None, Some(next)) => {
+                delta = next - centroid.mean();
+                max = next;
+            }
+            (Some(prev), None) => {
+                delta = centroid.mean() - prev;
+                min = prev;
+            }
+            (Some(prev), Some(next)) => {
+                delta = (next - prev) / 2.0;
+                min = prev;
+                max = next;
             }
+            (None, None) => {}
         }
 
-        let value = self.centroids[pos].mean() + ((rank - t) / self.centroids[pos].weight() - 0.5) * delta;
+        let value = centroid.mean() + ((rank - t) / centroid.weight() - 0.5) * delta;
         Self::clamp(value, min, max)
     }
+
+    /// To estimate the fraction of values lower than `x`, counting half of those equal to it.
+    ///
+    /// This is the inverse of `estimate_quantile`: it interpolates linearly between the
+    /// centroid means, where the rank is known to be the weight before the centroid plus
+    /// half its own weight, and from `min` and `max`, which are exact.
+    pub fn estimate_cdf(&self, x: f64) -> f64 {
+        let count_: f64 = self.count();
+        if count_ > 0.0 {
+            self.estimate_rank(x) / count_
+        } else {
+            0.0
+        }
+    }
+
+    /// To estimate the number of values lower than `x`, counting half of those equal to it.
+    pub fn estimate_rank(&self, x: f64) -> f64 {
+        if !self.buffer.is_empty() {
+            return self.merged().estimate_rank(x);
+        }
+
+        RankCursor::new(self).rank(x)
+    }
+
+    /// Same as `estimate_cdf` for each of `xs`, answered in a single sweep over the centroids.
+    pub fn estimate_cdfs(&self, xs: &[f64]) -> Vec<f64> {
+        let count_: f64 = self.count();
+        let mut result: Vec<f64> = self.estimate_ranks(xs);
+        for cdf in result.iter_mut() {
+            *cdf = if count_ > 0.0 { *cdf / count_ } else { 0.0 };
+        }
+
+        result
+    }
+
+    /// Same as `estimate_rank` for each of `xs`, answered in a single sweep over the centroids.
+    pub fn estimate_ranks(&self, xs: &[f64]) -> Vec<f64> {
+        if !self.buffer.is_empty() {
+            return self.merged().estimate_ranks(xs);
+        }
+
+        let mut order: Vec<usize> = (0..xs.len()).collect();
+        order.sort_by_key(|&i| OrderedFloat::from(xs[i]));
+
+        let mut result: Vec<f64> = vec![0.0; xs.len()];
+        let mut cursor = RankCursor::new(self);
+        for i in order {
+            result[i] = cursor.rank(xs[i]);
+        }
+
+        result
+    }
+}
+
+/// Walks the centroids of a flushed digest to estimate ranks asked in increasing order,
+/// see `estimate_cdf`. Centroids sharing a mean are treated as one.
+struct RankCursor<I: Iterator<Item = Centroid>> {
+    centroids: std::iter::Peekable<I>,
+    empty: bool,
+    count: f64,
+    max: f64,
+    min: f64,
+    group: Option<(f64, f64)>,
+    t: f64,
+    prev: (f64, f64),
+}
+
+impl<'a> RankCursor<std::iter::Cloned<std::slice::Iter<'a, Centroid>>> {
+    fn new(digest: &'a TDigest) -> Self {
+        RankCursor::from_parts(
+            digest.centroids.iter().cloned(),
+            digest.count.into_inner(),
+            digest.max.into_inner(),
+            digest.min.into_inner(),
+        )
+    }
+}
+
+impl<I: Iterator<Item = Centroid>> RankCursor<I> {
+    fn from_parts(centroids: I, count: f64, max: f64, min: f64) -> Self {
+        let mut centroids = centroids.peekable();
+        let empty = centroids.peek().is_none();
+        RankCursor {
+            centroids,
+            empty,
+            count,
+            max,
+            min,
+            group: None,
+            t: 0.0,
+            prev: (min, 0.0),
+        }
+    }
+
+    fn rank(&mut self, x: f64) -> f64 {
+        if self.empty {
+            return 0.0;
+        }
+
+        let (count_, min, max) = (self.count, self.min, self.max);
+        if x < min {
+            return 0.0;
+        } else if x > max {
+            return count_;
+        } else if min == max {
+            return count_ / 2.0;
+        }
+
+        let interpolate = |(x0, r0): (f64, f64), (x1, r1): (f64, f64)| r0 + (r1 - r0) * (x - x0) / (x1 - x0);
+
+        while let Some((mean, weight)) = self.next_group() {
+            let knot: (f64, f64) = (mean, self.t + weight / 2.0);
+            if x < mean {
+                return interpolate(self.prev, knot);
+            } else if x == mean {
+                return knot.1;
+            }
+
+            self.prev = knot;
+            self.t += weight;
+            self.group = None;
+        }
+
+        interpolate(self.prev, (max, count_))
+    }
+
+    /// Mean and total weight of the next centroids sharing a mean, without consuming them.
+    fn next_group(&mut self) -> Option<(f64, f64)> {
+        if self.group.is_none() {
+            let first = self.centroids.next()?;
+            let mut weight: f64 = first.weight();
+            while let Some(centroid) = self.centroids.next_if(|c| c.mean() == first.mean()) {
+                weight += centroid.weight();
+            }
+            self.group = Some((first.mean(), weight));
+        }
+
+        self.group
+    }
 }
 
 #[cfg(test)]
@@ -531,6 +1334,368 @@ mod tests {
         assert!(percentage < 0.01);
     }
 
+    #[test]
+    fn test_insert_matches_merge() {
+        let values: Vec<f64> = (1..=10_000).map(|v| f64::from((v * 7919) % 10_007)).collect();
+
+        let mut t = TDigest::new_with_size(100);
+        t.insert_many(&values[..1234]);
+        for &v in &values[1234..] {
+            t.insert(v);
+        }
+
+        assert_eq!(t.count(), 10_000.0);
+        assert_eq!(t.min(), values.iter().cloned().fold(f64::INFINITY, f64::min));
+        assert_eq!(t.max(), values.iter().cloned().fold(f64::NEG_INFINITY, f64::max));
+
+        let expected = TDigest::new_with_size(100).merge_unsorted(values);
+        for q in [0.1, 0.25, 0.5, 0.75, 0.9, 0.99] {
+            let ans = t.estimate_quantile(q);
+            let percentage: f64 = (expected.estimate_quantile(q) - ans).abs() / ans;
+            assert!(percentage < 0.01);
+        }
+
+        let before_flush = t.estimate_quantile(0.5);
+        t.flush();
+        assert_eq!(t.count(), 10_000.0);
+        assert_eq!(t.estimate_quantile(0.5), before_flush);
+
+        let mut huge = TDigest::new_with_size(usize::MAX);
+        assert_eq!(huge.buffer_capacity(), MAX_BUFFER_CAPACITY);
+        huge.insert_many(&[3.0, 1.0, 2.0]);
+        assert_eq!((huge.count(), huge.min(), huge.max()), (3.0, 1.0, 3.0));
+    }
+
+    #[test]
+    fn test_merge_weighted() {
+        let values: Vec<(f64, f64)> = vec![(3.0, 10.0), (1.0, 20.0), (2.0, 0.0), (5.0, 10.0), (4.0, -1.0)];
+        let t = TDigest::new_with_size(100).merge_weighted_unsorted(values);
+
+        assert_eq!(t.count(), 40.0);
+        assert_eq!(t.sum(), 100.0);
+        assert_eq!(t.min(), 1.0);
+        assert_eq!(t.max(), 5.0);
+
+        let t = t.merge_weighted_sorted(vec![(0.5, 2.5), (6.0, 1.5)]);
+        assert_eq!(t.count(), 44.0);
+        assert_eq!(t.sum(), 110.25);
+        assert_eq!(t.estimate_quantile(0.0), 0.5);
+        assert_eq!(t.estimate_quantile(1.0), 6.0);
+
+        let ans = t.estimate_quantile(0.5);
+        assert!((1.0..3.0).contains(&ans));
+    }
+
+    #[test]
+    fn test_collect_and_extend() {
+        let t: TDigest = (1..=1_000_000).map(f64::from).collect();
+        assert_eq!(t.count(), 1_000_000.0);
+
+        let ans = t.estimate_quantile(0.99);
+        let expected: f64 = 990_000.0;
+        let percentage: f64 = (expected - ans).abs() / expected;
+        assert!(percentage < 0.01);
+
+        let mut t = TDigest::new_with_size(50);
+        let values: Vec<f64> = (1..=1_000).map(f64::from).collect();
+        t.extend(&values);
+        t.extend(values.iter().map(|v| v + 1_000.0));
+        assert_eq!(t.count(), 2_000.0);
+        assert_eq!(t.max(), 2_000.0);
+
+        let ans = t.estimate_quantile(0.5);
+        let expected: f64 = 1_000.0;
+        let percentage: f64 = (expected - ans).abs() / expected;
+        assert!(percentage < 0.01);
+    }
+
+    #[test]
+    fn test_merge_slices() {
+        let values: Vec<f64> = (1..=10_000).map(f64::from).collect();
+
+        let mut t = TDigest::new_with_size(100);
+        t.merge_sorted_slice(&values);
+        assert_eq!(t, TDigest::new_with_size(100).merge_sorted(values.clone()));
+
+        let mut batch: Vec<f64> = values.iter().rev().cloned().collect();
+        t.merge_unsorted_slice(&mut batch);
+        assert_eq!(batch, values);
+        assert_eq!(t.count(), 20_000.0);
+
+        let capacity = t.centroids.capacity() + t.scratch.capacity();
+        for _ in 0..10 {
+            batch.reverse();
+            t.merge_unsorted_slice(&mut batch);
+        }
+        assert_eq!(t.centroids.capacity() + t.scratch.capacity(), capacity);
+
+        let ans = t.estimate_quantile(0.5);
+        let expected: f64 = 5_000.0;
+        let percentage: f64 = (expected - ans).abs() / expected;
+        assert!(percentage < 0.01);
+    }
+
+    #[test]
+    fn test_non_finite_policy() {
+        let values = vec![3.0, f64::NAN, 1.0, f64::INFINITY, 2.0, f64::NEG_INFINITY];
+
+        let t = TDigest::new_with_size(100).merge_unsorted(values.clone());
+        assert_eq!(t.count(), 3.0);
+        assert_eq!(t.rejected(), 3);
+        assert_eq!(t.max(), 3.0);
+        assert_eq!(t.estimate_quantile(1.0), 3.0);
+        assert_eq!(t.sum(), 6.0);
+
+        let mut t = TDigest::new_with_size(100).with_non_finite_policy(NonFinitePolicy::Clamp);
+        t.insert_many(&values);
+        assert_eq!(t.count(), 5.0);
+        assert_eq!(t.rejected(), 1);
+        assert_eq!(t.min(), 1.0);
+        assert_eq!(t.max(), 3.0);
+        assert_eq!(t.sum(), 10.0);
+
+        let mut t = TDigest::new_with_size(100).with_non_finite_policy(NonFinitePolicy::Reject);
+        assert_eq!(
+            t.try_insert_many(&values[2..]),
+            Err(TDigestError::NonFiniteValue(f64::INFINITY))
+        );
+        assert_eq!(
+            t.try_merge_weighted_sorted(vec![(1.0, 1.0), (2.0, 0.0)]),
+            Err(TDigestError::InvalidWeight(0.0))
+        );
+        assert!(t.is_empty());
+
+        let t = t.merge_weighted_sorted(vec![(1.0, 2.0), (2.0, 3.0)]);
+        assert_eq!(t.count(), 5.0);
+        assert_eq!(t.rejected(), 0);
+
+        let skipped = TDigest::new_with_size(100).merge_weighted_sorted(vec![(1.0, f64::NAN), (2.0, -1.0)]);
+        assert!(skipped.is_empty());
+        assert_eq!(skipped.rejected(), 2);
+    }
+
+    #[test]
+    fn test_non_finite_policy_clamp_round_trip() {
+        let mut t = TDigest::new_with_size(100).with_non_finite_policy(NonFinitePolicy::Clamp);
+        t.insert(f64::INFINITY);
+        assert!(t.is_empty());
+        assert_eq!(t.rejected(), 1);
+
+        t.insert_many(&[f64::INFINITY, f64::INFINITY, 1.0]);
+        t.insert(f64::NEG_INFINITY);
+        assert_eq!(t.count(), 4.0);
+        assert_eq!(t.sum(), 4.0);
+        assert_eq!((t.min(), t.max()), (1.0, 1.0));
+
+        let t = t.merge_sorted(vec![f64::NEG_INFINITY, 0.5, 2.0, f64::INFINITY]);
+        assert_eq!((t.min(), t.max()), (0.5, 2.0));
+        assert_eq!(t.sum(), 9.0);
+
+        let decoded = TDigest::from_bytes(&t.to_bytes()).unwrap();
+        assert_eq!(decoded.sum(), t.sum());
+        assert_eq!(decoded.centroids(), t.centroids());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_non_finite_policy_reject_panics() {
+        let mut t = TDigest::new_with_size(100).with_non_finite_policy(NonFinitePolicy::Reject);
+        t.insert(f64::NAN);
+    }
+
+    #[test]
+    fn test_try_new() {
+        let centroids = vec![
+            Centroid::new(1.0, 2.0),
+            Centroid::new(2.0, 3.0),
+            Centroid::new(5.0, 1.0),
+        ];
+        let t = TDigest::try_new(centroids.clone(), 13.0, 6.0, 5.0, 0.5, 100).unwrap();
+        assert_eq!(t.count(), 6.0);
+        assert_eq!(t.estimate_quantile(1.0), 5.0);
+
+        let err = TDigest::try_new(centroids.clone(), 13.0, 7.0, 5.0, 0.5, 100);
+        assert_eq!(
+            err,
+            Err(TDigestError::CountMismatch {
+                count: 7.0,
+                weight: 6.0
+            })
+        );
+
+        let err = TDigest::try_new(centroids.clone(), 13.0, 6.0, 4.0, 0.5, 100);
+        assert_eq!(err, Err(TDigestError::InvalidBounds { min: 0.5, max: 4.0 }));
+
+        let err = TDigest::try_new(centroids.iter().rev().cloned().collect(), 13.0, 6.0, 5.0, 0.5, 100);
+        assert_eq!(err, Err(TDigestError::UnsortedCentroids));
+
+        let err = TDigest::try_new(vec![Centroid::new(1.0, -1.0)], 13.0, -1.0, 5.0, 0.5, 100);
+        assert_eq!(err, Err(TDigestError::InvalidWeight(-1.0)));
+
+        let err = TDigest::try_new(centroids.clone(), 13.0, 6.0, 5.0, 0.5, 0);
+        assert_eq!(err, Err(TDigestError::InvalidMaxSize(0)));
+
+        let t = TDigest::try_new(Vec::new(), 0.0, 0.0, f64::NAN, f64::NAN, 100).unwrap();
+        assert!(t.is_empty());
+
+        let err = TDigest::try_new(Vec::new(), 0.0, 0.0, 1.0, 9.0, 100);
+        assert_eq!(err, Err(TDigestError::InvalidBounds { min: 9.0, max: 1.0 }));
+        assert!(TDigest::try_new(Vec::new(), 0.0, 0.0, 9.0, 1.0, 100).is_err());
+        assert!(TDigest::try_new(Vec::new(), 0.0, 0.0, f64::NAN, 1.0, 100).is_err());
+        assert!(TDigest::try_new(Vec::new(), 5.0, 0.0, f64::NAN, f64::NAN, 100).is_err());
+    }
+
+    #[test]
+    fn test_new_compresses_to_max_size() {
+        let centroids: Vec<Centroid> = (1..=1_000).map(|v| Centroid::new(f64::from(v), 1.0)).collect();
+        let t = TDigest::try_new(centroids, 500_500.0, 1_000.0, 1_000.0, 1.0, 10).unwrap();
+
+        assert_eq!(t.max_size(), 10);
+        assert!(t.centroids.len() < 20);
+        assert_eq!(t.count(), 1_000.0);
+    }
+
+    #[test]
+    fn test_checked_queries() {
+        let t = TDigest::new_with_size(100);
+        assert_eq!(t.try_estimate_quantile(0.5), Err(TDigestError::Empty));
+        assert_eq!(t.checked_mean(), None);
+        assert_eq!(t.checked_min(), None);
+        assert_eq!(t.checked_max(), None);
+
+        let t = t.merge_sorted(vec![1.0, 2.0, 3.0]);
+        assert_eq!(t.try_estimate_quantile(1.5), Err(TDigestError::InvalidQuantile(1.5)));
+        assert_eq!(t.try_estimate_quantile(1.0), Ok(3.0));
+        assert_eq!(t.checked_mean(), Some(2.0));
+        assert_eq!(t.checked_min(), Some(1.0));
+        assert_eq!(t.checked_max(), Some(3.0));
+    }
+
+    #[test]
+    fn test_estimate_rank() {
+        let t = TDigest::new_with_size(1_000).merge_sorted(vec![1.0, 2.0, 2.0, 2.0, 3.0, 5.0]);
+
+        assert_eq!(t.estimate_rank(0.0), 0.0);
+        assert_eq!(t.estimate_rank(1.0), 0.5);
+        assert_eq!(t.estimate_rank(1.5), 1.5);
+        assert_eq!(t.estimate_rank(2.0), 2.5);
+        assert_eq!(t.estimate_rank(4.0), 5.0);
+        assert_eq!(t.estimate_rank(5.0), 5.5);
+        assert_eq!(t.estimate_rank(6.0), 6.0);
+        assert_eq!(t.estimate_cdf(2.0), 2.5 / 6.0);
+
+        let t = TDigest::new_with_size(100).merge_sorted(vec![7.0; 10]);
+        assert_eq!(t.estimate_cdf(7.0), 0.5);
+        assert_eq!(TDigest::new_with_size(100).estimate_cdf(7.0), 0.0);
+    }
+
+    #[test]
+    fn test_estimate_cdf_against_uniform_distro() {
+        let t = TDigest::new_with_size(100);
+        let values: Vec<f64> = (1..=1_000_000).map(f64::from).collect();
+        let t = t.merge_sorted(values);
+
+        assert_eq!(t.estimate_cdf(0.0), 0.0);
+        assert_eq!(t.estimate_cdf(1_000_000.0), 1.0);
+
+        for q in [0.01, 0.1, 0.25, 0.5, 0.75, 0.9, 0.99] {
+            let ans = t.estimate_cdf(q * 1_000_000.0);
+            let percentage: f64 = (q - ans).abs() / q;
+            assert!(percentage < 0.01);
+        }
+
+        for centroid in t.centroids.iter() {
+            let q = t.estimate_cdf(centroid.mean());
+            let percentage: f64 = (centroid.mean() - t.estimate_quantile(q)).abs() / centroid.mean();
+            assert!(percentage < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_batch_queries_match_single_queries() {
+        let values: Vec<(f64, f64)> = (1..=100_000)
+            .map(|v| (f64::from(v % 997), 0.5 + f64::from(v % 7)))
+            .collect();
+        let t = TDigest::new_with_size(100).merge_weighted_unsorted(values);
+
+        let qs = vec![
+            0.99,
+            0.0,
+            0.5,
+            0.25,
+            1.0,
+            0.001,
+            0.75,
+            0.5000001,
+            1.5,
+            -0.5,
+            0.9,
+            f64::NAN,
+            0.1,
+        ];
+        let answers = t.estimate_quantiles(&qs);
+        for (q, ans) in qs.iter().zip(answers) {
+            assert_eq!(t.estimate_quantile(*q).to_bits(), ans.to_bits());
+        }
+
+        let xs = vec![500.0, -1.0, 0.0, 996.0, 1000.0, 3.5, 250.0, 250.0, 12.25, 998.5];
+        let answers = t.estimate_cdfs(&xs);
+        for (x, ans) in xs.iter().zip(answers) {
+            assert_eq!(t.estimate_cdf(*x), ans);
+        }
+
+        let t = TDigest::new_with_size(1_000).merge_sorted(vec![1.0, 2.0, 2.0, 2.0, 3.0, 5.0]);
+        assert_eq!(t.estimate_ranks(&[6.0, 2.0, 1.5, 0.0]), vec![6.0, 2.5, 1.5, 0.0]);
+        assert!(TDigest::new_with_size(100).estimate_quantiles(&[0.5]) == vec![0.0]);
+    }
+
+    #[test]
+    fn test_scales_against_uniform_distro() {
+        let values: Vec<f64> = (1..=1_000_000).map(f64::from).collect();
+
+        for scale in [Scale::Quadratic, Scale::K0, Scale::K1, Scale::K2, Scale::K3] {
+            let t = TDigest::new_with_size(100).with_scale(scale);
+            let t = t.merge_sorted(values.clone());
+            assert_eq!(t.scale(), scale);
+            assert!(t.centroids.len() < 200);
+
+            let digests: Vec<TDigest> = (0..10).map(|_| t.clone()).collect();
+            let merged = TDigest::merge_digests(digests);
+            assert_eq!(merged.scale(), scale);
+
+            for q in [0.01, 0.1, 0.5, 0.9, 0.99] {
+                for digest in [&t, &merged] {
+                    let ans = digest.estimate_quantile(q);
+                    let rank_error: f64 = (q - ans / 1_000_000.0).abs();
+                    assert!(rank_error < 0.01);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_centroids() {
+        let mut t = TDigest::new_with_size(10);
+        t.insert_many(&[1.0, 2.0, 3.0]);
+        assert!(t.centroids().is_empty());
+
+        t.flush();
+        assert_eq!(
+            t.centroids(),
+            &[
+                Centroid::new(1.0, 1.0),
+                Centroid::new(2.0, 1.0),
+                Centroid::new(3.0, 1.0)
+            ]
+        );
+
+        let t = t.merge_sorted((1..=1_000).map(f64::from).collect());
+        let weights: Vec<f64> = t.cumulative_weights().map(|(_, w)| w).collect();
+        assert_eq!(weights.len(), t.centroids().len());
+        assert!(weights.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(weights.last(), Some(&t.count()));
+    }
+
     #[test]
     fn test_merge_sorted_against_uniform_distro() {
         let t = TDigest::new_with_size(100);
@@ -611,9 +1776,7 @@ mod tests {
     fn test_merge_sorted_against_skewed_distro() {
         let t = TDigest::new_with_size(100);
         let mut values: Vec<f64> = (1..=600_000).map(f64::from).collect();
-        for _ in 0..400_000 {
-            values.push(1_000_000.0);
-        }
+        values.resize(1_000_000, 1_000_000.0);
 
         let t = t.merge_sorted(values);
 
@@ -639,9 +1802,7 @@ mod tests {
     fn test_merge_unsorted_against_skewed_distro() {
         let t = TDigest::new_with_size(100);
         let mut values: Vec<f64> = (1..=600_000).map(f64::from).collect();
-        for _ in 0..400_000 {
-            values.push(1_000_000.0);
-        }
+        values.resize(1_000_000, 1_000_000.0);
 
         let t = t.merge_unsorted(values);
 
@@ -663,6 +1824,34 @@ mod tests {
         assert!(percentage < 0.01);
     }
 
+    #[test]
+    fn test_max_size_does_not_size_allocations() {
+        let t = TDigest::new_with_size(usize::MAX).merge_sorted(vec![1.0, 2.0, 3.0]);
+        assert_eq!(t.count(), 3.0);
+
+        let merged = TDigest::merge_digests(vec![t.clone(), t]);
+        assert_eq!(merged.count(), 6.0);
+        assert_eq!(merged.max_size(), usize::MAX);
+    }
+
+    #[test]
+    fn test_merge_empty_digests() {
+        let empty = TDigest::new_with_size(500)
+            .with_scale(Scale::K2)
+            .with_non_finite_policy(NonFinitePolicy::Clamp)
+            .merge_sorted(vec![f64::NAN]);
+        assert_eq!(empty.rejected(), 1);
+
+        let t = TDigest::merge_digests(vec![empty.clone(), empty]);
+        assert!(t.is_empty());
+        assert_eq!(t.max_size(), 500);
+        assert_eq!(t.scale(), Scale::K2);
+        assert_eq!(t.non_finite_policy(), NonFinitePolicy::Clamp);
+        assert_eq!(t.rejected(), 2);
+
+        assert_eq!(TDigest::merge_digests(Vec::new()), TDigest::default());
+    }
+
     #[test]
     fn test_merge_digests() {
         let mut digests: Vec<TDigest> = Vec::new();
@@ -0,0 +1,204 @@
+//! A `metrics` recorder keeping a digest per histogram, behind the `use_metrics` feature.
+//!
+//! ```rust
+//! use tdigest::metrics::TDigestRecorder;
+//!
+//! let recorder = TDigestRecorder::new(100);
+//! let handle = recorder.handle();
+//! metrics::with_local_recorder(&recorder, || {
+//!     metrics::histogram!("latency", "route" => "/").record(12.0);
+//! });
+//!
+//! let snapshot = handle.snapshot(&[0.5, 0.99]);
+//! assert_eq!(snapshot[0].digest.count(), 1.0);
+//! ```
+//!
+//! Counters and gauges are not recorded.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use ::metrics::{Counter, Gauge, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder, SharedString, Unit};
+
+use crate::sync::lock;
+use crate::TDigest;
+
+/// Records every histogram into its own `TDigest` of `max_size`.
+#[derive(Debug)]
+pub struct TDigestRecorder {
+    max_size: usize,
+    registry: Arc<Registry>,
+}
+
+/// Reads the digests of a `TDigestRecorder`, which may have been installed globally.
+#[derive(Debug, Clone)]
+pub struct TDigestHandle {
+    registry: Arc<Registry>,
+}
+
+/// State of one histogram at the time of `TDigestHandle::snapshot`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistogramSnapshot {
+    pub name: String,
+    pub labels: Vec<(String, String)>,
+    /// Each requested quantile with its estimate.
+    pub quantiles: Vec<(f64, f64)>,
+    /// The digest itself, for instance to be encoded with `to_bytes` and merged elsewhere.
+    pub digest: TDigest,
+}
+
+type Registry = Mutex<HashMap<Key, Arc<DigestHistogram>>>;
+
+#[derive(Debug)]
+struct DigestHistogram {
+    digest: Mutex<TDigest>,
+}
+
+impl HistogramFn for DigestHistogram {
+    fn record(&self, value: f64) {
+        lock(&self.digest).insert(value);
+    }
+}
+
+impl TDigestRecorder {
+    pub fn new(max_size: usize) -> Self {
+        TDigestRecorder {
+            max_size,
+            registry: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn handle(&self) -> TDigestHandle {
+        TDigestHandle {
+            registry: Arc::clone(&self.registry),
+        }
+    }
+}
+
+impl Default for TDigestRecorder {
+    fn default() -> Self {
+        TDigestRecorder::new(100)
+    }
+}
+
+impl Recorder for TDigestRecorder {
+    fn describe_counter(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_gauge(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_histogram(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn register_counter(&self, _key: &Key, _metadata: &Metadata<'_>) -> Counter {
+        Counter::noop()
+    }
+
+    fn register_gauge(&self, _key: &Key, _metadata: &Metadata<'_>) -> Gauge {
+        Gauge::noop()
+    }
+
+    fn register_histogram(&self, key: &Key, _metadata: &Metadata<'_>) -> Histogram {
+        let histogram = lock(&self.registry)
+            .entry(key.clone())
+            .or_insert_with(|| {
+                Arc::new(DigestHistogram {
+                    digest: Mutex::new(TDigest::new_with_size(self.max_size)),
+                })
+            })
+            .clone();
+        Histogram::from_arc(histogram)
+    }
+}
+
+impl TDigestHandle {
+    /// Flushes and copies every digest, sorted by name then labels, with their estimates of `quantiles`.
+    pub fn snapshot(&self, quantiles: &[f64]) -> Vec<HistogramSnapshot> {
+        let histograms: Vec<(Key, Arc<DigestHistogram>)> = lock(&self.registry)
+            .iter()
+            .map(|(k, h)| (k.clone(), Arc::clone(h)))
+            .collect();
+
+        let mut snapshot: Vec<HistogramSnapshot> = histograms
+            .into_iter()
+            .map(|(key, histogram)| {
+                let digest = {
+                    let mut digest = lock(&histogram.digest);
+                    digest.flush();
+                    digest.clone()
+                };
+
+                HistogramSnapshot {
+                    name: key.name().to_string(),
+                    labels: key
+                        .labels()
+                        .map(|l| (l.key().to_string(), l.value().to_string()))
+                        .collect(),
+                    quantiles: quantiles
+                        .iter()
+                        .cloned()
+                        .zip(digest.estimate_quantiles(quantiles))
+                        .collect(),
+                    digest,
+                }
+            })
+            .collect();
+
+        snapshot.sort_by(|a, b| (&a.name, &a.labels).cmp(&(&b.name, &b.labels)));
+        snapshot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records_per_key_digests() {
+        let recorder = TDigestRecorder::new(50);
+        let handle = recorder.handle();
+
+        ::metrics::with_local_recorder(&recorder, || {
+            for i in 1..=1000 {
+                ::metrics::histogram!("latency", "route" => "/b").record(f64::from(i));
+                ::metrics::histogram!("latency", "route" => "/a").record(f64::from(i) * 2.0);
+            }
+            ::metrics::histogram!("size").record(3.0);
+            ::metrics::counter!("requests").increment(1);
+        });
+
+        let snapshot = handle.snapshot(&[0.5, 0.99]);
+        assert_eq!(snapshot.len(), 3);
+
+        assert_eq!(snapshot[0].name, "latency");
+        assert_eq!(snapshot[0].labels, vec![("route".to_string(), "/a".to_string())]);
+        assert_eq!(snapshot[0].digest.count(), 1000.0);
+        assert_eq!(snapshot[0].digest.max_size(), 50);
+        assert_eq!(snapshot[0].digest.max(), 2000.0);
+        let (q, ans) = snapshot[0].quantiles[1];
+        assert_eq!(q, 0.99);
+        let expected: f64 = 1980.0;
+        let percentage: f64 = (expected - ans).abs() / expected;
+        assert!(percentage < 0.01);
+
+        assert_eq!(snapshot[1].labels, vec![("route".to_string(), "/b".to_string())]);
+        assert_eq!(snapshot[2].name, "size");
+        assert_eq!(snapshot[2].quantiles, vec![(0.5, 3.0), (0.99, 3.0)]);
+
+        let bytes = snapshot[1].digest.to_bytes();
+        assert_eq!(TDigest::from_bytes(&bytes).unwrap(), snapshot[1].digest);
+    }
+
+    #[test]
+    fn test_handles_share_histograms() {
+        let recorder = TDigestRecorder::default();
+        let first =
+            recorder.register_histogram(&Key::from_name("h"), &Metadata::new("t", ::metrics::Level::INFO, None));
+        let second =
+            recorder.register_histogram(&Key::from_name("h"), &Metadata::new("t", ::metrics::Level::INFO, None));
+        first.record(1.0);
+        second.record(2.0);
+
+        let snapshot = recorder.handle().snapshot(&[]);
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].digest.count(), 2.0);
+    }
+}
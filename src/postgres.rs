@@ -0,0 +1,219 @@
+//! Text representation of the `tdigest` type of the Postgres extension.
+//!
+//! ```text
+//! flags 1 count 4 compression 100 centroids 3 (1.0, 1) (2.0, 2) (3.0, 1)
+//! ```
+//!
+//! With flag 1 each centroid is its mean and its count, with flag 0, written by older versions
+//! of the extension, its sum and its count. Counts are integers and the compression, which
+//! maps to `max_size`, must be in `[10, 10000]`. The representation stores no bounds, so the
+//! minimum and maximum of a parsed digest are its first and last means.
+
+use std::fmt::Write;
+use std::str::FromStr;
+
+use crate::{Centroid, TDigest, TDigestError};
+
+const STORES_MEAN: u32 = 1;
+
+const MIN_COMPRESSION: usize = 10;
+const MAX_COMPRESSION: usize = 10_000;
+
+/// Formats `digest` like the output function of the `tdigest` type.
+///
+/// Fails if the compression is out of range or a weight is not an integer.
+pub fn to_text(digest: &TDigest) -> Result<String, TDigestError> {
+    let digest = digest.merged();
+    check_compression(digest.max_size())?;
+
+    let mut text = format!(
+        "flags {} count {} compression {} centroids {}",
+        STORES_MEAN,
+        digest.count(),
+        digest.max_size(),
+        digest.centroids.len()
+    );
+    for centroid in digest.centroids.iter() {
+        let weight = centroid.weight();
+        if !(weight.fract() == 0.0 && weight <= i64::MAX as f64) {
+            return Err(TDigestError::InvalidArgument("centroid counts must be integers"));
+        }
+        write!(text, " ({:?}, {})", centroid.mean(), weight as i64).unwrap();
+    }
+
+    Ok(text)
+}
+
+/// Parses the output of the `tdigest` type, failing on anything inconsistent.
+pub fn from_text(text: &str) -> Result<TDigest, TDigestError> {
+    let mut parser = Parser { text };
+
+    parser.expect("flags")?;
+    let flags: u32 = parser.number()?;
+    if flags & !STORES_MEAN != 0 {
+        return Err(TDigestError::InvalidEncoding("unknown flags"));
+    }
+    parser.expect("count")?;
+    let count: i64 = parser.number()?;
+    parser.expect("compression")?;
+    let compression: usize = parser.number()?;
+    check_compression(compression)?;
+    parser.expect("centroids")?;
+    let len: usize = parser.number()?;
+
+    let mut centroids: Vec<Centroid> = Vec::new();
+    let mut sum: f64 = 0.0;
+    let mut total: i64 = 0;
+    for _ in 0..len {
+        parser.expect("(")?;
+        let value: f64 = parser.number()?;
+        parser.expect(",")?;
+        let weight: i64 = parser.number()?;
+        parser.expect(")")?;
+
+        if weight <= 0 {
+            return Err(TDigestError::InvalidWeight(weight as f64));
+        }
+        total = total
+            .checked_add(weight)
+            .ok_or(TDigestError::InvalidEncoding("count out of range"))?;
+
+        let weight = weight as f64;
+        let mean = if flags & STORES_MEAN != 0 {
+            value
+        } else {
+            value / weight
+        };
+        sum += mean * weight;
+        centroids.push(Centroid::new(mean, weight));
+    }
+    parser.finish()?;
+
+    if total != count {
+        return Err(TDigestError::CountMismatch {
+            count: count as f64,
+            weight: total as f64,
+        });
+    }
+
+    let (min, max) = match (centroids.first(), centroids.last()) {
+        (Some(first), Some(last)) => (first.mean(), last.mean()),
+        _ => (f64::NAN, f64::NAN),
+    };
+    TDigest::try_new(centroids, sum, count as f64, max, min, compression)
+}
+
+fn check_compression(compression: usize) -> Result<(), TDigestError> {
+    if (MIN_COMPRESSION..=MAX_COMPRESSION).contains(&compression) {
+        Ok(())
+    } else {
+        Err(TDigestError::InvalidArgument("compression must be in [10, 10000]"))
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    fn expect(&mut self, token: &str) -> Result<(), TDigestError> {
+        self.text = self.text.trim_start();
+        match self.text.strip_prefix(token) {
+            Some(rest) => {
+                self.text = rest;
+                Ok(())
+            }
+            None => Err(TDigestError::InvalidEncoding("malformed tdigest text")),
+        }
+    }
+
+    fn number<T: FromStr>(&mut self) -> Result<T, TDigestError> {
+        self.text = self.text.trim_start();
+        let end = self
+            .text
+            .find(|c: char| c.is_whitespace() || c == ',' || c == ')' || c == '(')
+            .unwrap_or(self.text.len());
+
+        let (number, rest) = self.text.split_at(end);
+        self.text = rest;
+        number
+            .parse()
+            .map_err(|_| TDigestError::InvalidEncoding("malformed number"))
+    }
+
+    fn finish(&self) -> Result<(), TDigestError> {
+        if self.text.trim().is_empty() {
+            Ok(())
+        } else {
+            Err(TDigestError::InvalidEncoding("trailing characters"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::digest;
+
+    #[test]
+    fn test_to_text() {
+        assert_eq!(
+            to_text(&digest()).unwrap(),
+            "flags 1 count 4 compression 100 centroids 3 (1.0, 1) (2.0, 2) (3.0, 1)"
+        );
+        assert_eq!(
+            to_text(&TDigest::new_with_size(100)).unwrap(),
+            "flags 1 count 0 compression 100 centroids 0"
+        );
+
+        assert!(to_text(&TDigest::new_with_size(5)).is_err());
+        let weighted = TDigest::new_with_size(100).merge_weighted_unsorted(vec![(1.0, 0.5)]);
+        assert!(to_text(&weighted).is_err());
+    }
+
+    #[test]
+    fn test_from_text() {
+        // As printed by the extension, with six decimals.
+        let text = "flags 1 count 4 compression 100 centroids 3 (1.000000, 1) (2.000000, 2) (3.000000, 1)";
+        assert_eq!(from_text(text).unwrap(), digest());
+
+        let legacy = "flags 0 count 4 compression 100 centroids 3 (1.000000, 1) (4.000000, 2) (3.000000, 1)";
+        assert_eq!(from_text(legacy).unwrap(), digest());
+
+        let empty = from_text("flags 1 count 0 compression 100 centroids 0").unwrap();
+        assert!(empty.is_empty());
+        assert_eq!(empty.max_size(), 100);
+    }
+
+    #[test]
+    fn test_round_trip_and_merge() {
+        let t = TDigest::new_with_size(100).merge_unsorted((1..=10_000).map(f64::from).collect());
+        let parsed = from_text(&to_text(&t).unwrap()).unwrap();
+        assert_eq!(parsed.centroids(), t.centroids());
+
+        let merged = TDigest::merge_digests(vec![parsed, t]);
+        assert_eq!(merged.count(), 20_000.0);
+        let ans = merged.estimate_quantile(0.99);
+        let expected: f64 = 9900.0;
+        let percentage: f64 = (expected - ans).abs() / expected;
+        assert!(percentage < 0.01);
+    }
+
+    #[test]
+    fn test_rejects_malformed_text() {
+        let bad = [
+            "",
+            "flags 2 count 1 compression 100 centroids 1 (1.0, 1)",
+            "flags 1 count 2 compression 100 centroids 1 (1.0, 1)",
+            "flags 1 count 1 compression 5 centroids 1 (1.0, 1)",
+            "flags 1 count 1 compression 100 centroids 2 (1.0, 1)",
+            "flags 1 count 1 compression 100 centroids 1 (1.0, 1) (2.0, 1)",
+            "flags 1 count 0 compression 100 centroids 1 (1.0, 0)",
+            "flags 1 count 2 compression 100 centroids 2 (2.0, 1) (1.0, 1)",
+            "flags 1 count 1 compression 100 centroids 1 (abc, 1)",
+        ];
+        for text in bad.iter() {
+            assert!(from_text(text).is_err(), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,219 @@
+//! Prometheus text exposition: digests rendered as summaries, and digests approximated from
+//! classic histograms.
+
+use std::fmt::Write;
+
+use crate::{Centroid, TDigest, TDigestError};
+
+/// Quantiles commonly exposed by summaries.
+pub const DEFAULT_QUANTILES: [f64; 5] = [0.5, 0.9, 0.95, 0.99, 0.999];
+
+/// Number of centroids each histogram bucket is spread over, see `from_histogram`.
+const BUCKET_CENTROIDS: usize = 4;
+
+/// Renders `digest` as a `summary` metric family with one sample per quantile, `_sum` and
+/// `_count`. `labels` are added to every sample. An empty digest has `NaN` quantiles.
+pub fn render_summary(name: &str, help: &str, labels: &[(&str, &str)], digest: &TDigest, quantiles: &[f64]) -> String {
+    let digest = digest.merged();
+    let values: Vec<f64> = if digest.is_empty() {
+        vec![f64::NAN; quantiles.len()]
+    } else {
+        digest.estimate_quantiles(quantiles)
+    };
+
+    let mut text = String::new();
+    writeln!(text, "# HELP {} {}", name, escape(help, false)).unwrap();
+    writeln!(text, "# TYPE {} summary", name).unwrap();
+    for (q, value) in quantiles.iter().zip(values) {
+        let quantile = format!("{}", q);
+        let mut labels = labels.to_vec();
+        labels.push(("quantile", &quantile));
+        writeln!(text, "{}{} {}", name, render_labels(&labels), render_value(value)).unwrap();
+    }
+    writeln!(
+        text,
+        "{}_sum{} {}",
+        name,
+        render_labels(labels),
+        render_value(digest.sum())
+    )
+    .unwrap();
+    writeln!(
+        text,
+        "{}_count{} {}",
+        name,
+        render_labels(labels),
+        render_value(digest.count())
+    )
+    .unwrap();
+
+    text
+}
+
+/// Approximates the observations of a classic histogram by a digest of `max_size`.
+///
+/// `buckets` are the `(le, cumulative count)` pairs of the `_bucket` samples, in increasing
+/// `le` order and ending with `+Inf`, and `sum` is the `_sum` sample. Like `histogram_quantile`,
+/// observations are assumed uniformly spread within each bucket, the first bucket starting at
+/// 0 unless its bound is negative, and those of the `+Inf` bucket are put at the highest finite
+/// bound.
+pub fn from_histogram(buckets: &[(f64, f64)], sum: f64, max_size: usize) -> Result<TDigest, TDigestError> {
+    match buckets.last() {
+        Some(&(le, _)) if le == f64::INFINITY => {}
+        _ => return Err(TDigestError::InvalidArgument("the last bucket must be +Inf")),
+    }
+    if !sum.is_finite() {
+        return Err(TDigestError::NonFiniteValue(sum));
+    }
+
+    let mut centroids: Vec<Centroid> = Vec::new();
+    let mut previous: Option<(f64, f64)> = None;
+    let mut bounds: Option<(f64, f64)> = None;
+    for &(le, cumulative) in buckets.iter() {
+        if !(cumulative.is_finite() && cumulative >= 0.0) {
+            return Err(TDigestError::InvalidArgument(
+                "bucket counts must be finite and non-negative",
+            ));
+        }
+
+        let (lower, upper, weight) = match previous {
+            Some((prev_le, _)) if le.is_nan() || le <= prev_le => {
+                return Err(TDigestError::InvalidArgument("bucket bounds must increase"));
+            }
+            Some((_, prev_cumulative)) if cumulative < prev_cumulative => {
+                return Err(TDigestError::InvalidArgument("bucket counts must be cumulative"));
+            }
+            Some((prev_le, prev_cumulative)) if le == f64::INFINITY => (prev_le, prev_le, cumulative - prev_cumulative),
+            Some((prev_le, prev_cumulative)) => (prev_le, le, cumulative - prev_cumulative),
+            None if !le.is_finite() => return Err(TDigestError::InvalidArgument("a finite bucket is required")),
+            None if le > 0.0 => (0.0, le, cumulative),
+            None => (le, le, cumulative),
+        };
+        previous = Some((le, cumulative));
+
+        if weight <= 0.0 {
+            continue;
+        }
+
+        bounds = Some((bounds.map_or(lower, |(min, _)| min), upper));
+        if lower == upper {
+            centroids.push(Centroid::new(lower, weight));
+            continue;
+        }
+
+        let parts = BUCKET_CENTROIDS as f64;
+        for j in 0..BUCKET_CENTROIDS {
+            let mean = lower + (upper - lower) * (j as f64 + 0.5) / parts;
+            centroids.push(Centroid::new(mean, weight / parts));
+        }
+    }
+
+    let count: f64 = centroids.iter().map(|c| c.weight()).sum();
+    let (min, max) = bounds.unwrap_or((f64::NAN, f64::NAN));
+    TDigest::try_new(centroids, sum, count, max, min, max_size)
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value, true)))
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+fn render_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        format!("{}", value)
+    }
+}
+
+/// Escapes backslashes and line feeds, and double quotes in label values.
+fn escape(text: &str, quotes: bool) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '"' if quotes => escaped.push_str("\\\""),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_summary() {
+        let t = TDigest::new_with_size(100).merge_sorted(vec![1.0, 2.0, 3.0, 4.0]);
+        let text = render_summary(
+            "rpc_duration_seconds",
+            "RPC latency.",
+            &[("service", "a\"b")],
+            &t,
+            &[0.5, 1.0],
+        );
+        assert_eq!(
+            text,
+            concat!(
+                "# HELP rpc_duration_seconds RPC latency.\n",
+                "# TYPE rpc_duration_seconds summary\n",
+                "rpc_duration_seconds{service=\"a\\\"b\",quantile=\"0.5\"} 2.5\n",
+                "rpc_duration_seconds{service=\"a\\\"b\",quantile=\"1\"} 4\n",
+                "rpc_duration_seconds_sum{service=\"a\\\"b\"} 10\n",
+                "rpc_duration_seconds_count{service=\"a\\\"b\"} 4\n",
+            )
+        );
+
+        let text = render_summary("empty", "", &[], &TDigest::new_with_size(100), &[0.5]);
+        assert!(text.contains("empty{quantile=\"0.5\"} NaN\n"));
+        assert!(text.contains("empty_count 0\n"));
+    }
+
+    #[test]
+    fn test_from_histogram() {
+        // 1000 observations uniformly spread over [0, 100), plus 10 above the last bound.
+        let mut buckets: Vec<(f64, f64)> = (1..=10).map(|i| (f64::from(i) * 10.0, f64::from(i) * 100.0)).collect();
+        buckets.push((f64::INFINITY, 1010.0));
+
+        let t = from_histogram(&buckets, 55_000.0, 100).unwrap();
+        assert_eq!(t.count(), 1010.0);
+        assert_eq!(t.sum(), 55_000.0);
+        assert_eq!(t.min(), 0.0);
+        assert_eq!(t.max(), 100.0);
+
+        for &(q, expected) in [(0.25, 25.25), (0.5, 50.5), (0.9, 90.9)].iter() {
+            let ans = t.estimate_quantile(q);
+            let percentage: f64 = (expected - ans).abs() / expected;
+            assert!(percentage < 0.02, "{} {}", q, ans);
+        }
+
+        let merged = TDigest::merge_digests(vec![t.clone(), t]);
+        assert_eq!(merged.count(), 2020.0);
+    }
+
+    #[test]
+    fn test_from_invalid_histogram() {
+        let inf = f64::INFINITY;
+        assert!(from_histogram(&[], 0.0, 100).is_err());
+        assert!(from_histogram(&[(1.0, 1.0)], 1.0, 100).is_err());
+        assert!(from_histogram(&[(1.0, 2.0), (inf, 1.0)], 1.0, 100).is_err());
+        assert!(from_histogram(&[(2.0, 1.0), (1.0, 1.0), (inf, 1.0)], 1.0, 100).is_err());
+        assert!(from_histogram(&[(inf, 1.0)], 1.0, 100).is_err());
+
+        let empty = from_histogram(&[(1.0, 0.0), (inf, 0.0)], 0.0, 100).unwrap();
+        assert!(empty.is_empty());
+    }
+}
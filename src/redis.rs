@@ -0,0 +1,522 @@
+//! Semantics of the Redis Stack `TDIGEST.*` commands on top of `TDigest`.
+//!
+//! Each function answers like the command of the same name, including for empty digests
+//! (`NaN`, or `-2` for the ranks) and out-of-range arguments (an error). The queries use the
+//! interpolation of Redis, which differs from `TDigest::estimate_quantile`, so that the same
+//! centroids give the same answers. The centroids themselves only match Redis when no
+//! compression happened, since Redis compresses with a different scale function.
+
+use crate::{Centroid, TDigest, TDigestError};
+
+/// Compression of a digest created without `COMPRESSION`.
+pub const DEFAULT_COMPRESSION: usize = 100;
+
+/// `TDIGEST.ADD`: adds all of `values`, or none of them if one is NaN or infinite.
+pub fn add(digest: &mut TDigest, values: &[f64]) -> Result<(), TDigestError> {
+    if let Some(&value) = values.iter().find(|v| !v.is_finite()) {
+        return Err(TDigestError::NonFiniteValue(value));
+    }
+
+    digest.insert_many(values);
+    Ok(())
+}
+
+/// `TDIGEST.MERGE`: merges `sources` into a new digest.
+///
+/// Without `compression`, the largest compression of the sources is used. To merge into an
+/// existing destination without `OVERRIDE`, include it in `sources`.
+pub fn merge(sources: &[&TDigest], compression: Option<usize>) -> Result<TDigest, TDigestError> {
+    if sources.is_empty() {
+        return Err(TDigestError::InvalidArgument("numkeys should be positive"));
+    }
+
+    let compression = match compression {
+        Some(0) => return Err(TDigestError::InvalidArgument("compression should be positive")),
+        Some(compression) => compression,
+        None => sources.iter().map(|d| d.max_size()).max().unwrap(),
+    };
+
+    let mut digests: Vec<TDigest> = Vec::with_capacity(sources.len() + 1);
+    digests.push(TDigest::new_with_size(compression));
+    digests.extend(sources.iter().map(|&d| d.clone()));
+
+    let merged = TDigest::merge_digests(digests);
+    if merged.is_empty() {
+        Ok(TDigest::new_with_size(compression))
+    } else {
+        Ok(merged)
+    }
+}
+
+/// `TDIGEST.MIN`, `NaN` for an empty digest.
+pub fn min(digest: &TDigest) -> f64 {
+    digest.checked_min().unwrap_or(f64::NAN)
+}
+
+/// `TDIGEST.MAX`, `NaN` for an empty digest.
+pub fn max(digest: &TDigest) -> f64 {
+    digest.checked_max().unwrap_or(f64::NAN)
+}
+
+/// `TDIGEST.QUANTILE`: fails unless every quantile is in `[0, 1]`, `NaN`s for an empty digest.
+pub fn quantile(digest: &TDigest, quantiles: &[f64]) -> Result<Vec<f64>, TDigestError> {
+    if let Some(&q) = quantiles.iter().find(|q| !(0.0..=1.0).contains(*q)) {
+        return Err(TDigestError::InvalidQuantile(q));
+    }
+
+    let digest = digest.merged();
+    Ok(quantiles.iter().map(|&q| redis_quantile(&digest, q)).collect())
+}
+
+/// `TDIGEST.CDF`, `NaN`s for an empty digest.
+pub fn cdf(digest: &TDigest, values: &[f64]) -> Vec<f64> {
+    let digest = digest.merged();
+    values.iter().map(|&v| redis_cdf(&digest, v)).collect()
+}
+
+/// `TDIGEST.RANK`: the number of values lower than each of `values` plus half of those equal
+/// to it, rounded half down. `-1` below the minimum, `-2` for an empty digest.
+pub fn rank(digest: &TDigest, values: &[f64]) -> Vec<i64> {
+    ranks(digest, values, false)
+}
+
+/// `TDIGEST.REVRANK`: same as `rank`, counting the values greater than each of `values`.
+/// `-1` above the maximum, `-2` for an empty digest.
+pub fn revrank(digest: &TDigest, values: &[f64]) -> Vec<i64> {
+    ranks(digest, values, true)
+}
+
+/// `TDIGEST.BYRANK`: the value of each rank, `inf` for ranks past the last value and `NaN`s
+/// for an empty digest. Fails on negative ranks.
+pub fn byrank(digest: &TDigest, ranks: &[i64]) -> Result<Vec<f64>, TDigestError> {
+    by_ranks(digest, ranks, false)
+}
+
+/// `TDIGEST.BYREVRANK`: same as `byrank`, ranking from the maximum, `-inf` past the first value.
+pub fn byrevrank(digest: &TDigest, ranks: &[i64]) -> Result<Vec<f64>, TDigestError> {
+    by_ranks(digest, ranks, true)
+}
+
+/// `TDIGEST.TRIMMED_MEAN`: the mean of the values between the two quantiles, `NaN` for an
+/// empty digest.
+pub fn trimmed_mean(digest: &TDigest, low_cut: f64, high_cut: f64) -> Result<f64, TDigestError> {
+    if !((0.0..=1.0).contains(&low_cut) && (0.0..=1.0).contains(&high_cut)) {
+        return Err(TDigestError::InvalidArgument(
+            "low_cut_percentile and high_cut_percentile should be in [0,1]",
+        ));
+    }
+    if low_cut >= high_cut {
+        return Err(TDigestError::InvalidArgument(
+            "low_cut_percentile should be lower than high_cut_percentile",
+        ));
+    }
+
+    let digest = digest.merged();
+    if digest.is_empty() {
+        return Ok(f64::NAN);
+    }
+
+    let low_weight = (digest.count() * low_cut).floor();
+    let high_weight = (digest.count() * high_cut).ceil();
+
+    let mut count_done: f64 = 0.0;
+    let mut trimmed_sum: f64 = 0.0;
+    let mut trimmed_count: f64 = 0.0;
+    for centroid in digest.centroids.iter() {
+        let mut count_add = centroid.weight();
+        count_add -= (low_weight - count_done).max(0.0).min(count_add);
+        count_add = (high_weight - count_done).max(0.0).min(count_add);
+        count_done += centroid.weight();
+
+        trimmed_sum += centroid.mean() * count_add;
+        trimmed_count += count_add;
+        if count_done >= high_weight {
+            break;
+        }
+    }
+
+    Ok(trimmed_sum / trimmed_count)
+}
+
+fn ranks(digest: &TDigest, values: &[f64], reverse: bool) -> Vec<i64> {
+    let digest = digest.merged();
+    let size = digest.count();
+
+    values
+        .iter()
+        .map(|&value| {
+            if digest.is_empty() {
+                -2
+            } else if value < digest.min() {
+                if reverse {
+                    size as i64
+                } else {
+                    -1
+                }
+            } else if value > digest.max() {
+                if reverse {
+                    -1
+                } else {
+                    size as i64
+                }
+            } else {
+                let rank = redis_cdf(&digest, value) * size;
+                let rank = if reverse { size - rank } else { rank };
+                half_round_down(rank) as i64
+            }
+        })
+        .collect()
+}
+
+fn by_ranks(digest: &TDigest, ranks: &[i64], reverse: bool) -> Result<Vec<f64>, TDigestError> {
+    if ranks.iter().any(|&r| r < 0) {
+        return Err(TDigestError::InvalidArgument("rank needs to be non negative"));
+    }
+
+    let digest = digest.merged();
+    let size = digest.count();
+
+    Ok(ranks
+        .iter()
+        .map(|&r| {
+            let r = r as f64;
+            if digest.is_empty() {
+                f64::NAN
+            } else if r >= size {
+                if reverse {
+                    f64::NEG_INFINITY
+                } else {
+                    f64::INFINITY
+                }
+            } else if reverse {
+                redis_quantile(&digest, (size - r - 1.0) / size)
+            } else {
+                redis_quantile(&digest, r / size)
+            }
+        })
+        .collect())
+}
+
+/// Rounds to the nearest integer, halves towards zero.
+fn half_round_down(value: f64) -> f64 {
+    let int_part = value.trunc();
+    if (value - int_part).abs() <= 0.5 {
+        int_part
+    } else if int_part >= 0.0 {
+        int_part + 1.0
+    } else {
+        int_part - 1.0
+    }
+}
+
+fn weighted_average(x1: f64, w1: f64, x2: f64, w2: f64) -> f64 {
+    let (x1, w1, x2, w2) = if x1 <= x2 { (x1, w1, x2, w2) } else { (x2, w2, x1, w1) };
+    let x = (x1 * w1 + x2 * w2) / (w1 + w2);
+    x.min(x2).max(x1)
+}
+
+/// Quantile as interpolated by Redis: centroids of weight 1 are known to sit exactly at their
+/// mean and a single value is known to sit at each of `min` and `max`.
+fn redis_quantile(digest: &TDigest, q: f64) -> f64 {
+    let centroids: &[Centroid] = &digest.centroids;
+    let n = centroids.len();
+    if n == 0 {
+        return f64::NAN;
+    } else if n == 1 {
+        return centroids[0].mean();
+    }
+
+    let total_weight = digest.count();
+    let index = q * total_weight;
+    if index < 1.0 {
+        return digest.min();
+    }
+
+    let left_weight = centroids[0].weight();
+    if left_weight > 1.0 && index < left_weight / 2.0 {
+        return digest.min() + (index - 1.0) / (left_weight / 2.0 - 1.0) * (centroids[0].mean() - digest.min());
+    }
+
+    if index > total_weight - 1.0 {
+        return digest.max();
+    }
+
+    let right_weight = centroids[n - 1].weight();
+    if right_weight > 1.0 && total_weight - index <= right_weight / 2.0 {
+        return digest.max()
+            - (total_weight - index - 1.0) / (right_weight / 2.0 - 1.0) * (digest.max() - centroids[n - 1].mean());
+    }
+
+    let mut weight_so_far = left_weight / 2.0;
+    for i in 0..n - 1 {
+        let dw = (centroids[i].weight() + centroids[i + 1].weight()) / 2.0;
+        if weight_so_far + dw > index {
+            let mut left_unit = 0.0;
+            if centroids[i].weight() == 1.0 {
+                if index - weight_so_far < 0.5 {
+                    return centroids[i].mean();
+                }
+                left_unit = 0.5;
+            }
+
+            let mut right_unit = 0.0;
+            if centroids[i + 1].weight() == 1.0 {
+                if weight_so_far + dw - index <= 0.5 {
+                    return centroids[i + 1].mean();
+                }
+                right_unit = 0.5;
+            }
+
+            let z1 = index - weight_so_far - left_unit;
+            let z2 = weight_so_far + dw - index - right_unit;
+            return weighted_average(centroids[i].mean(), z2, centroids[i + 1].mean(), z1);
+        }
+
+        weight_so_far += dw;
+    }
+
+    let z1 = index - total_weight - right_weight / 2.0;
+    let z2 = right_weight / 2.0 - z1;
+    weighted_average(centroids[n - 1].mean(), z1, digest.max(), z2)
+}
+
+/// CDF as interpolated by Redis, see `redis_quantile`.
+fn redis_cdf(digest: &TDigest, value: f64) -> f64 {
+    let centroids: &[Centroid] = &digest.centroids;
+    let n = centroids.len();
+    if n == 0 {
+        return f64::NAN;
+    }
+
+    let (min, max) = (digest.min(), digest.max());
+    if value < min {
+        return 0.0;
+    } else if value > max {
+        return 1.0;
+    }
+
+    if n == 1 {
+        let width = max - min;
+        return if value - min <= width {
+            0.5
+        } else {
+            (value - min) / width
+        };
+    }
+
+    let total_weight = digest.count();
+
+    let left_mean = centroids[0].mean();
+    if value < left_mean {
+        let width = left_mean - min;
+        return if width <= 0.0 {
+            0.0
+        } else if value == min {
+            0.5 / total_weight
+        } else {
+            (1.0 + (value - min) / width * (centroids[0].weight() / 2.0 - 1.0)) / total_weight
+        };
+    }
+
+    let right_mean = centroids[n - 1].mean();
+    if value > right_mean {
+        let width = max - right_mean;
+        return if width <= 0.0 {
+            1.0
+        } else if value == max {
+            1.0 - 0.5 / total_weight
+        } else {
+            1.0 - (1.0 + (max - value) / width * (centroids[n - 1].weight() / 2.0 - 1.0)) / total_weight
+        };
+    }
+
+    let mut weight_so_far: f64 = 0.0;
+    let mut i = 0;
+    while i < n - 1 {
+        let (mean, weight) = (centroids[i].mean(), centroids[i].weight());
+        let (next_mean, next_weight) = (centroids[i + 1].mean(), centroids[i + 1].weight());
+
+        if mean == value {
+            let dw: f64 = centroids[i..]
+                .iter()
+                .take_while(|c| c.mean() == value)
+                .map(|c| c.weight())
+                .sum();
+            return (weight_so_far + dw / 2.0) / total_weight;
+        } else if mean <= value && value < next_mean {
+            if next_mean - mean <= 0.0 {
+                return (weight_so_far + (weight + next_weight) / 2.0) / total_weight;
+            }
+
+            let mut left_excluded = 0.0;
+            let mut right_excluded = 0.0;
+            if weight == 1.0 {
+                if next_weight == 1.0 {
+                    return (weight_so_far + 1.0) / total_weight;
+                }
+                left_excluded = 0.5;
+            } else if next_weight == 1.0 {
+                right_excluded = 0.5;
+            }
+
+            let dw = (weight + next_weight) / 2.0 - left_excluded - right_excluded;
+            let base = weight_so_far + weight / 2.0 + left_excluded;
+            return (base + dw * (value - mean) / (next_mean - mean)) / total_weight;
+        }
+
+        weight_so_far += weight;
+        i += 1;
+    }
+
+    1.0 - 0.5 / total_weight
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create(values: &[f64]) -> TDigest {
+        let mut digest = TDigest::new_with_size(1000);
+        add(&mut digest, values).unwrap();
+        digest
+    }
+
+    // The examples below are the ones of the Redis command reference.
+
+    #[test]
+    fn test_quantile_and_cdf() {
+        let t = create(&[
+            1.0, 2.0, 2.0, 3.0, 3.0, 3.0, 4.0, 4.0, 4.0, 4.0, 5.0, 5.0, 5.0, 5.0, 5.0,
+        ]);
+
+        let qs = [0.0, 0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9, 1.0];
+        let expected = vec![1.0, 2.0, 3.0, 3.0, 4.0, 4.0, 4.0, 5.0, 5.0, 5.0, 5.0];
+        assert_eq!(quantile(&t, &qs).unwrap(), expected);
+
+        let values = [0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
+        let expected = vec![0.0, 0.5 / 15.0, 2.0 / 15.0, 4.5 / 15.0, 8.0 / 15.0, 12.5 / 15.0, 1.0];
+        assert_eq!(cdf(&t, &values), expected);
+
+        assert_eq!(quantile(&t, &[1.5]), Err(TDigestError::InvalidQuantile(1.5)));
+    }
+
+    #[test]
+    fn test_rank_and_revrank() {
+        let t = create(&[10.0, 20.0, 30.0, 40.0, 50.0, 60.0]);
+        let values = [0.0, 10.0, 20.0, 30.0, 40.0, 50.0, 60.0, 70.0];
+        assert_eq!(rank(&t, &values), vec![-1, 0, 1, 2, 3, 4, 5, 6]);
+        assert_eq!(revrank(&t, &values), vec![6, 5, 4, 3, 2, 1, 0, -1]);
+
+        let t = create(&[10.0, 10.0, 10.0, 10.0, 20.0, 20.0]);
+        assert_eq!(rank(&t, &[10.0, 20.0]), vec![2, 5]);
+        assert_eq!(revrank(&t, &[10.0, 20.0]), vec![4, 1]);
+
+        // Half ranks round down both ways.
+        let t = create(&[10.0, 10.0, 10.0]);
+        assert_eq!(rank(&t, &[10.0]), vec![1]);
+        assert_eq!(revrank(&t, &[10.0]), vec![1]);
+    }
+
+    #[test]
+    fn test_byrank_and_byrevrank() {
+        let t = create(&[
+            1.0, 2.0, 2.0, 3.0, 3.0, 3.0, 4.0, 4.0, 4.0, 4.0, 5.0, 5.0, 5.0, 5.0, 5.0,
+        ]);
+        let ranks: Vec<i64> = (0..=15).collect();
+
+        let expected = vec![
+            1.0,
+            2.0,
+            2.0,
+            3.0,
+            3.0,
+            3.0,
+            4.0,
+            4.0,
+            4.0,
+            4.0,
+            5.0,
+            5.0,
+            5.0,
+            5.0,
+            5.0,
+            f64::INFINITY,
+        ];
+        assert_eq!(byrank(&t, &ranks).unwrap(), expected);
+
+        let expected = vec![
+            5.0,
+            5.0,
+            5.0,
+            5.0,
+            5.0,
+            4.0,
+            4.0,
+            4.0,
+            4.0,
+            3.0,
+            3.0,
+            3.0,
+            2.0,
+            2.0,
+            1.0,
+            f64::NEG_INFINITY,
+        ];
+        assert_eq!(byrevrank(&t, &ranks).unwrap(), expected);
+
+        assert!(byrank(&t, &[-1]).is_err());
+    }
+
+    #[test]
+    fn test_trimmed_mean() {
+        let t = create(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0]);
+        assert_eq!(trimmed_mean(&t, 0.1, 0.6), Ok(4.0));
+        assert_eq!(trimmed_mean(&t, 0.3, 0.9), Ok(6.5));
+        assert_eq!(trimmed_mean(&t, 0.0, 1.0), Ok(5.5));
+
+        assert!(trimmed_mean(&t, 0.6, 0.1).is_err());
+        assert!(trimmed_mean(&t, -0.1, 0.5).is_err());
+    }
+
+    #[test]
+    fn test_empty_digest() {
+        let t = TDigest::new_with_size(DEFAULT_COMPRESSION);
+
+        assert!(min(&t).is_nan());
+        assert!(max(&t).is_nan());
+        assert!(quantile(&t, &[0.5]).unwrap()[0].is_nan());
+        assert!(cdf(&t, &[1.0])[0].is_nan());
+        assert_eq!(rank(&t, &[1.0]), vec![-2]);
+        assert_eq!(revrank(&t, &[1.0]), vec![-2]);
+        assert!(byrank(&t, &[0]).unwrap()[0].is_nan());
+        assert!(trimmed_mean(&t, 0.1, 0.9).unwrap().is_nan());
+    }
+
+    #[test]
+    fn test_add_and_merge() {
+        let mut t = TDigest::new_with_size(DEFAULT_COMPRESSION);
+        assert!(matches!(
+            add(&mut t, &[1.0, f64::NAN]),
+            Err(TDigestError::NonFiniteValue(_))
+        ));
+        assert_eq!(
+            add(&mut t, &[f64::INFINITY]),
+            Err(TDigestError::NonFiniteValue(f64::INFINITY))
+        );
+        assert!(t.is_empty());
+
+        let a = create(&[1.0, 2.0, 3.0]);
+        let b = create(&[4.0, 5.0]);
+        let merged = merge(&[&a, &b], None).unwrap();
+        assert_eq!(merged.max_size(), 1000);
+        assert_eq!(merged.count(), 5.0);
+        assert_eq!(min(&merged), 1.0);
+        assert_eq!(max(&merged), 5.0);
+
+        let merged = merge(&[&a, &TDigest::new_with_size(10)], Some(50)).unwrap();
+        assert_eq!(merged.max_size(), 50);
+        assert_eq!(merged.count(), 3.0);
+
+        assert!(merge(&[], None).is_err());
+    }
+}
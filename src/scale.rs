@@ -0,0 +1,187 @@
+//! Scale functions bounding the size of the centroids.
+//!
+//! A scale function maps a quantile `q` to a scale `k`. While compressing, a centroid may
+//! only grow as long as it spans at most one unit of `k`, so the steeper the function is
+//! around a quantile, the smaller and more accurate the centroids are there. `K0` to `K3`
+//! are the functions described in the t-digest paper, `Quadratic` is the one used by folly.
+
+use std::f64::consts::PI;
+
+#[cfg(feature = "use_serde")]
+use serde::{Deserialize, Serialize};
+
+/// Clamps `q` to `[1 / n, 1 - 1 / n]` for the logarithmic scale functions, which diverge at
+/// 0 and 1: no centroid is smaller than a single value anyway.
+fn clamp_to_one_value(q: f64, n: f64) -> f64 {
+    let epsilon = (1.0 / n).min(0.5);
+    q.clamp(epsilon, 1.0 - epsilon)
+}
+
+/// Maps quantiles to scales for a digest of `max_size` `d` holding `n` values.
+pub trait ScaleFunction {
+    /// Scale of the quantile `q`.
+    fn k(&self, q: f64, d: f64, n: f64) -> f64;
+
+    /// Quantile of the scale `k`, the inverse of `k`.
+    fn q(&self, k: f64, d: f64, n: f64) -> f64;
+}
+
+/// Piecewise quadratic scale, the default, following folly.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Quadratic;
+
+impl ScaleFunction for Quadratic {
+    fn k(&self, q: f64, d: f64, _n: f64) -> f64 {
+        let q = q.clamp(0.0, 1.0);
+        if q >= 0.5 {
+            d * (1.0 - ((1.0 - q) / 2.0).sqrt())
+        } else {
+            d * (q / 2.0).sqrt()
+        }
+    }
+
+    fn q(&self, k: f64, d: f64, _n: f64) -> f64 {
+        let k_div_d = k / d;
+        if k_div_d >= 0.5 {
+            let base = 1.0 - k_div_d;
+            1.0 - 2.0 * base * base
+        } else {
+            2.0 * k_div_d * k_div_d
+        }
+    }
+}
+
+/// Linear scale `k0`, giving centroids of equal weight.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct K0;
+
+impl ScaleFunction for K0 {
+    fn k(&self, q: f64, d: f64, _n: f64) -> f64 {
+        d * q / 2.0
+    }
+
+    fn q(&self, k: f64, d: f64, _n: f64) -> f64 {
+        (2.0 * k / d).clamp(0.0, 1.0)
+    }
+}
+
+/// Arcsine scale `k1`, accurate at both tails.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct K1;
+
+impl ScaleFunction for K1 {
+    fn k(&self, q: f64, d: f64, _n: f64) -> f64 {
+        d / (2.0 * PI) * (2.0 * q.clamp(0.0, 1.0) - 1.0).asin()
+    }
+
+    fn q(&self, k: f64, d: f64, _n: f64) -> f64 {
+        let k = k.clamp(-d / 4.0, d / 4.0);
+        ((2.0 * PI * k / d).sin() + 1.0) / 2.0
+    }
+}
+
+/// Logarithmic scale `k2`, whose slope depends on the number of values.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct K2;
+
+impl K2 {
+    fn normalizer(d: f64, n: f64) -> f64 {
+        d / (4.0 * (n / d).max(1.0).ln() + 24.0)
+    }
+}
+
+impl ScaleFunction for K2 {
+    fn k(&self, q: f64, d: f64, n: f64) -> f64 {
+        let q = clamp_to_one_value(q, n);
+        Self::normalizer(d, n) * (q / (1.0 - q)).ln()
+    }
+
+    fn q(&self, k: f64, d: f64, n: f64) -> f64 {
+        let w = (k / Self::normalizer(d, n)).exp();
+        w / (1.0 + w)
+    }
+}
+
+/// Logarithmic scale `k3`, the most accurate at the extreme tails.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct K3;
+
+impl K3 {
+    fn normalizer(d: f64, n: f64) -> f64 {
+        d / (4.0 * (n / d).max(1.0).ln() + 21.0)
+    }
+}
+
+impl ScaleFunction for K3 {
+    fn k(&self, q: f64, d: f64, n: f64) -> f64 {
+        let q = clamp_to_one_value(q, n);
+        if q <= 0.5 {
+            Self::normalizer(d, n) * (2.0 * q).ln()
+        } else {
+            -Self::normalizer(d, n) * (2.0 * (1.0 - q)).ln()
+        }
+    }
+
+    fn q(&self, k: f64, d: f64, n: f64) -> f64 {
+        if k <= 0.0 {
+            (k / Self::normalizer(d, n)).exp() / 2.0
+        } else {
+            1.0 - (-k / Self::normalizer(d, n)).exp() / 2.0
+        }
+    }
+}
+
+/// Scale function of a `TDigest`, one of the implementations of `ScaleFunction` above.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[cfg_attr(feature = "use_serde", derive(Serialize, Deserialize))]
+pub enum Scale {
+    #[default]
+    Quadratic,
+    K0,
+    K1,
+    K2,
+    K3,
+}
+
+impl ScaleFunction for Scale {
+    fn k(&self, q: f64, d: f64, n: f64) -> f64 {
+        match self {
+            Scale::Quadratic => Quadratic.k(q, d, n),
+            Scale::K0 => K0.k(q, d, n),
+            Scale::K1 => K1.k(q, d, n),
+            Scale::K2 => K2.k(q, d, n),
+            Scale::K3 => K3.k(q, d, n),
+        }
+    }
+
+    fn q(&self, k: f64, d: f64, n: f64) -> f64 {
+        match self {
+            Scale::Quadratic => Quadratic.q(k, d, n),
+            Scale::K0 => K0.q(k, d, n),
+            Scale::K1 => K1.q(k, d, n),
+            Scale::K2 => K2.q(k, d, n),
+            Scale::K3 => K3.q(k, d, n),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_q_inverts_k() {
+        for scale in [Scale::Quadratic, Scale::K0, Scale::K1, Scale::K2, Scale::K3] {
+            let mut prev = f64::NEG_INFINITY;
+            for i in 1..100 {
+                let q = f64::from(i) / 100.0;
+                let k = scale.k(q, 100.0, 10_000.0);
+                assert!(k > prev);
+                prev = k;
+
+                let ans = scale.q(k, 100.0, 10_000.0);
+                assert!((ans - q).abs() < 1e-9);
+            }
+        }
+    }
+}
@@ -0,0 +1,284 @@
+//! `Serialize` and `Deserialize` for `TDigest`, in the shape documented on the type.
+
+use std::fmt;
+
+use serde::de::{Error, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{Centroid, Scale, TDigest, MAX_DECODED_SIZE};
+
+const VERSION: u32 = 1;
+
+impl Serialize for TDigest {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let digest = self.merged();
+        let bound = |value: f64| {
+            if digest.is_empty() || value.is_nan() {
+                None
+            } else {
+                Some(value)
+            }
+        };
+
+        let mut state = serializer.serialize_struct("TDigest", 8)?;
+        state.serialize_field("version", &VERSION)?;
+        state.serialize_field("max_size", &digest.max_size())?;
+        state.serialize_field("scale", &digest.scale())?;
+        state.serialize_field("count", &digest.count())?;
+        state.serialize_field("sum", &digest.sum())?;
+        state.serialize_field("min", &bound(digest.min()))?;
+        state.serialize_field("max", &bound(digest.max()))?;
+        state.serialize_field("centroids", &Pairs(&digest.centroids))?;
+        state.end()
+    }
+}
+
+/// Serializes centroids as `[mean, weight]` pairs.
+struct Pairs<'a>(&'a [Centroid]);
+
+impl<'a> Serialize for Pairs<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter().map(|c| (c.mean(), c.weight())))
+    }
+}
+
+/// Fields of the current version, also their order in formats without field names.
+const FIELDS: &[&str] = &[
+    "version",
+    "max_size",
+    "scale",
+    "count",
+    "sum",
+    "min",
+    "max",
+    "centroids",
+];
+
+#[derive(Deserialize)]
+#[serde(field_identifier, rename_all = "snake_case")]
+enum Field {
+    Version,
+    MaxSize,
+    Scale,
+    Count,
+    Sum,
+    Min,
+    Max,
+    Centroids,
+    #[serde(other)]
+    Other,
+}
+
+/// A centroid: a `[mean, weight]` pair, or a `{"mean", "weight"}` map in the derived layout of
+/// 0.2 and before.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AnyCentroid {
+    Pair(f64, f64),
+    Map { mean: f64, weight: f64 },
+}
+
+/// The fields read, before they are checked by `TDigest::try_new`.
+struct Parts {
+    version: Option<u32>,
+    max_size: usize,
+    scale: Scale,
+    count: f64,
+    sum: f64,
+    min: Option<f64>,
+    max: Option<f64>,
+    centroids: Vec<Centroid>,
+}
+
+impl Parts {
+    fn into_digest<E: Error>(self) -> Result<TDigest, E> {
+        // Without a version, this is the derived layout of 0.2 and before.
+        if let Some(version) = self.version.filter(|&v| v != VERSION) {
+            return Err(E::custom(format!("unsupported TDigest version {}", version)));
+        }
+        if self.max_size > MAX_DECODED_SIZE {
+            return Err(E::custom(format!(
+                "max_size {} above {}",
+                self.max_size, MAX_DECODED_SIZE
+            )));
+        }
+
+        let min = self.min.unwrap_or(f64::NAN);
+        let max = self.max.unwrap_or(f64::NAN);
+        let digest =
+            TDigest::try_new(self.centroids, self.sum, self.count, max, min, self.max_size).map_err(E::custom)?;
+        Ok(digest.with_scale(self.scale))
+    }
+}
+
+struct DigestVisitor;
+
+impl<'de> Visitor<'de> for DigestVisitor {
+    type Value = TDigest;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter.write_str("a TDigest")
+    }
+
+    /// Formats without field names only hold the current version, with every field in order.
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<TDigest, A::Error> {
+        fn element<'de, A: SeqAccess<'de>, T: Deserialize<'de>>(seq: &mut A, index: usize) -> Result<T, A::Error> {
+            seq.next_element()?
+                .ok_or_else(|| A::Error::invalid_length(index, &DigestVisitor))
+        }
+
+        let parts = Parts {
+            version: Some(element(&mut seq, 0)?),
+            max_size: element(&mut seq, 1)?,
+            scale: element(&mut seq, 2)?,
+            count: element(&mut seq, 3)?,
+            sum: element(&mut seq, 4)?,
+            min: element(&mut seq, 5)?,
+            max: element(&mut seq, 6)?,
+            centroids: element::<A, Vec<(f64, f64)>>(&mut seq, 7)?
+                .into_iter()
+                .map(|(mean, weight)| Centroid::new(mean, weight))
+                .collect(),
+        };
+        parts.into_digest()
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<TDigest, A::Error> {
+        let mut version: Option<u32> = None;
+        let mut max_size: Option<usize> = None;
+        let mut scale: Option<Scale> = None;
+        let mut count: Option<f64> = None;
+        let mut sum: Option<f64> = None;
+        let mut min: Option<Option<f64>> = None;
+        let mut max: Option<Option<f64>> = None;
+        let mut centroids: Option<Vec<AnyCentroid>> = None;
+
+        fn set<T, E: Error>(slot: &mut Option<T>, value: T, name: &'static str) -> Result<(), E> {
+            match slot.replace(value) {
+                Some(_) => Err(E::duplicate_field(name)),
+                None => Ok(()),
+            }
+        }
+
+        while let Some(field) = map.next_key::<Field>()? {
+            match field {
+                Field::Version => set(&mut version, map.next_value()?, "version")?,
+                Field::MaxSize => set(&mut max_size, map.next_value()?, "max_size")?,
+                Field::Scale => set(&mut scale, map.next_value()?, "scale")?,
+                Field::Count => set(&mut count, map.next_value()?, "count")?,
+                Field::Sum => set(&mut sum, map.next_value()?, "sum")?,
+                Field::Min => set(&mut min, map.next_value()?, "min")?,
+                Field::Max => set(&mut max, map.next_value()?, "max")?,
+                Field::Centroids => set(&mut centroids, map.next_value()?, "centroids")?,
+                Field::Other => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+
+        let parts = Parts {
+            version,
+            max_size: max_size.ok_or_else(|| A::Error::missing_field("max_size"))?,
+            scale: scale.unwrap_or_default(),
+            count: count.ok_or_else(|| A::Error::missing_field("count"))?,
+            sum: sum.ok_or_else(|| A::Error::missing_field("sum"))?,
+            min: min.flatten(),
+            max: max.flatten(),
+            centroids: centroids
+                .ok_or_else(|| A::Error::missing_field("centroids"))?
+                .into_iter()
+                .map(|centroid| match centroid {
+                    AnyCentroid::Pair(mean, weight) | AnyCentroid::Map { mean, weight } => Centroid::new(mean, weight),
+                })
+                .collect(),
+        };
+        parts.into_digest()
+    }
+}
+
+/// Reads the current layout, and the derived layout of 0.2 and before when there is no `version`.
+impl<'de> Deserialize<'de> for TDigest {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_struct("TDigest", FIELDS, DigestVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_shape() {
+        let t = TDigest::new_with_size(100).merge_sorted(vec![1.0, 2.0]);
+        assert_eq!(
+            serde_json::to_string(&t).unwrap(),
+            r#"{"version":1,"max_size":100,"scale":"Quadratic","count":2.0,"sum":3.0,"min":1.0,"max":2.0,"centroids":[[1.0,1.0],[2.0,1.0]]}"#
+        );
+
+        let empty = TDigest::new_with_size(100);
+        let json = serde_json::to_string(&empty).unwrap();
+        assert_eq!(
+            json,
+            r#"{"version":1,"max_size":100,"scale":"Quadratic","count":0.0,"sum":0.0,"min":null,"max":null,"centroids":[]}"#
+        );
+        assert_eq!(serde_json::from_str::<TDigest>(&json).unwrap(), empty);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut t = TDigest::new_with_size(50).with_scale(Scale::K1);
+        t.insert_many(&(1..=1000).map(f64::from).collect::<Vec<f64>>());
+
+        let json = serde_json::to_string(&t).unwrap();
+        let decoded: TDigest = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.centroids(), t.merged().centroids());
+        assert_eq!(decoded.scale(), Scale::K1);
+        assert_eq!(decoded.estimate_quantile(0.9), t.estimate_quantile(0.9));
+    }
+
+    #[test]
+    fn test_rejects_invalid_digests() {
+        let bad = [
+            r#"{"version":2,"max_size":100,"count":0.0,"sum":0.0,"min":null,"max":null,"centroids":[]}"#,
+            r#"{"version":1,"max_size":0,"count":0.0,"sum":0.0,"min":null,"max":null,"centroids":[]}"#,
+            r#"{"version":1,"max_size":1000000000000,"count":0.0,"sum":0.0,"min":null,"max":null,"centroids":[]}"#,
+            r#"{"version":1,"max_size":100,"count":3.0,"sum":2.0,"min":2.0,"max":2.0,"centroids":[[2.0,1.0]]}"#,
+            r#"{"version":1,"max_size":100,"count":1.0,"sum":2.0,"min":null,"max":null,"centroids":[[2.0,1.0]]}"#,
+            r#"{"version":1,"max_size":100,"count":2.0,"sum":3.0,"min":1.0,"max":2.0,"centroids":[[2.0,1.0],[1.0,1.0]]}"#,
+            r#"{"version":1,"version":1,"max_size":100,"count":0.0,"sum":0.0,"min":null,"max":null,"centroids":[]}"#,
+            r#"{"version":1,"count":0.0,"sum":0.0,"min":null,"max":null,"centroids":[]}"#,
+            r#"{"centroids":[{"mean":2.0,"weight":1.0}],"max_size":100,"sum":2.0,"count":1.0,"max":1.0,"min":1.0}"#,
+        ];
+        for json in bad.iter() {
+            assert!(serde_json::from_str::<TDigest>(json).is_err(), "{}", json);
+        }
+    }
+
+    #[test]
+    fn test_reads_legacy_layout() {
+        // Written by the derived `Serialize` of 0.2.3.
+        let json = concat!(
+            r#"{"centroids":[{"mean":1.5,"weight":2.0},{"mean":5.5,"weight":6.0},{"mean":13.5,"weight":10.0},"#,
+            r#"{"mean":25.5,"weight":14.0},{"mean":41.5,"weight":18.0},{"mean":59.5,"weight":18.0},"#,
+            r#"{"mean":75.5,"weight":14.0},{"mean":87.5,"weight":10.0},{"mean":95.5,"weight":6.0},"#,
+            r#"{"mean":99.5,"weight":2.0}],"max_size":10,"sum":5050.0,"count":100.0,"max":100.0,"min":1.0}"#,
+        );
+        let t: TDigest = serde_json::from_str(json).unwrap();
+        assert_eq!(t.max_size(), 10);
+        assert_eq!(t.scale(), Scale::Quadratic);
+        assert_eq!(t.centroids().len(), 10);
+        assert_eq!(t.centroids()[4], Centroid::new(41.5, 18.0));
+        assert_eq!((t.count(), t.sum(), t.min(), t.max()), (100.0, 5050.0, 1.0, 100.0));
+
+        let json = r#"{"centroids":[],"max_size":100,"sum":0.0,"count":0.0,"max":null,"min":null}"#;
+        assert_eq!(
+            serde_json::from_str::<TDigest>(json).unwrap(),
+            TDigest::new_with_size(100)
+        );
+
+        // Read back in the current layout.
+        let decoded: TDigest = serde_json::from_str(&serde_json::to_string(&t).unwrap()).unwrap();
+        assert_eq!(decoded, t);
+    }
+}
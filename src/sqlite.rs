@@ -0,0 +1,256 @@
+//! SQLite functions over digests, behind the `use_sqlite` feature.
+//!
+//! `register_functions` adds to a connection:
+//!
+//! - `tdigest(x [, max_size])`, aggregating values into a digest blob of `max_size`, 100 by
+//!   default and at most 100000, `MAX_DECODED_SIZE`,
+//! - `tdigest_merge(blob)`, aggregating digest blobs into one,
+//! - `tdigest_quantile(blob, q)` and `tdigest_cdf(blob, x)`, estimating from a digest blob.
+//!
+//! Blobs are encoded with `TDigest::to_bytes`, so they can be decoded by `TDigest::from_bytes`
+//! and merged with digests from elsewhere. `NULL` inputs are skipped by the aggregates and give
+//! `NULL` from the estimates, and so do empty digests. The `tdigest-sqlite` package in `sqlite/`
+//! builds these functions as a loadable extension.
+//!
+//! ```rust
+//! use rusqlite::Connection;
+//!
+//! let conn = Connection::open_in_memory().unwrap();
+//! tdigest::sqlite::register_functions(&conn).unwrap();
+//!
+//! let p50: f64 = conn
+//!     .query_row(
+//!         "WITH RECURSIVE n(value) AS (SELECT 1 UNION ALL SELECT value + 1 FROM n WHERE value < 100)
+//!          SELECT tdigest_quantile(tdigest(value), 0.5) FROM n",
+//!         [],
+//!         |row| row.get(0),
+//!     )
+//!     .unwrap();
+//! assert!((p50 - 50.5).abs() < 1.0);
+//! ```
+
+use std::mem;
+
+use rusqlite::functions::{Aggregate, Context, FunctionFlags};
+use rusqlite::types::ValueRef;
+use rusqlite::{Connection, Error, Result};
+
+use crate::{TDigest, TDigestError, TDigestView, MAX_DECODED_SIZE};
+
+const DEFAULT_MAX_SIZE: usize = 100;
+
+/// Number of digests `tdigest_merge` accumulates before merging them.
+const MERGE_BATCH: usize = 32;
+
+/// Registers the digest functions on `conn`.
+pub fn register_functions(conn: &Connection) -> Result<()> {
+    let flags = FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC;
+
+    conn.create_aggregate_function("tdigest", 1, flags, DigestAggregate)?;
+    conn.create_aggregate_function("tdigest", 2, flags, DigestAggregate)?;
+    conn.create_aggregate_function("tdigest_merge", 1, flags, MergeAggregate)?;
+    conn.create_scalar_function("tdigest_quantile", 2, flags, |ctx| {
+        let (Some(view), Some(q)) = (view_arg(ctx, 0)?, ctx.get::<Option<f64>>(1)?) else {
+            return Ok(None);
+        };
+
+        if !(0.0..=1.0).contains(&q) {
+            return Err(user_error(TDigestError::InvalidQuantile(q)));
+        }
+        Ok((!view.is_empty()).then(|| view.estimate_quantile(q)))
+    })?;
+    conn.create_scalar_function("tdigest_cdf", 2, flags, |ctx| {
+        let (Some(view), Some(x)) = (view_arg(ctx, 0)?, ctx.get::<Option<f64>>(1)?) else {
+            return Ok(None);
+        };
+
+        Ok((!view.is_empty()).then(|| view.estimate_cdf(x)))
+    })
+}
+
+struct DigestAggregate;
+
+struct MergeAggregate;
+
+impl Aggregate<TDigest, Option<Vec<u8>>> for DigestAggregate {
+    fn init(&self, ctx: &mut Context<'_>) -> Result<TDigest> {
+        if ctx.len() < 2 {
+            return Ok(TDigest::new_with_size(DEFAULT_MAX_SIZE));
+        }
+
+        match ctx.get::<i64>(1)? {
+            max_size if (1..=MAX_DECODED_SIZE as i64).contains(&max_size) => {
+                Ok(TDigest::new_with_size(max_size as usize))
+            }
+            _ => Err(user_error(TDigestError::InvalidArgument(
+                "max_size must be in [1, 100000]",
+            ))),
+        }
+    }
+
+    fn step(&self, ctx: &mut Context<'_>, digest: &mut TDigest) -> Result<()> {
+        match ctx.get::<Option<f64>>(0)? {
+            Some(value) => digest.try_insert(value).map_err(user_error),
+            None => Ok(()),
+        }
+    }
+
+    fn finalize(&self, _: &mut Context<'_>, digest: Option<TDigest>) -> Result<Option<Vec<u8>>> {
+        Ok(digest.filter(|d| !d.is_empty()).map(|d| d.to_bytes()))
+    }
+}
+
+impl Aggregate<Vec<TDigest>, Option<Vec<u8>>> for MergeAggregate {
+    fn init(&self, _: &mut Context<'_>) -> Result<Vec<TDigest>> {
+        Ok(Vec::new())
+    }
+
+    fn step(&self, ctx: &mut Context<'_>, digests: &mut Vec<TDigest>) -> Result<()> {
+        let Some(view) = view_arg(ctx, 0)? else { return Ok(()) };
+        if view.is_empty() {
+            return Ok(());
+        }
+
+        digests.push(view.to_tdigest());
+        if digests.len() >= MERGE_BATCH {
+            let merged = TDigest::merge_digests(mem::take(digests));
+            digests.push(merged);
+        }
+        Ok(())
+    }
+
+    fn finalize(&self, _: &mut Context<'_>, digests: Option<Vec<TDigest>>) -> Result<Option<Vec<u8>>> {
+        Ok(digests
+            .filter(|d| !d.is_empty())
+            .map(|d| TDigest::merge_digests(d).to_bytes()))
+    }
+}
+
+/// Reads a digest blob argument, `None` when it is `NULL`.
+fn view_arg<'a>(ctx: &'a Context<'_>, idx: usize) -> Result<Option<TDigestView<'a>>> {
+    match ctx.get_raw(idx) {
+        ValueRef::Null => Ok(None),
+        ValueRef::Blob(bytes) => TDigestView::new(bytes).map(Some).map_err(user_error),
+        value => Err(Error::InvalidFunctionParameterType(idx, value.data_type())),
+    }
+}
+
+fn user_error(err: TDigestError) -> Error {
+    Error::UserFunctionError(Box::new(err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connection() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        register_functions(&conn).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE requests (route TEXT, latency REAL);
+             WITH RECURSIVE n(value) AS (SELECT 1 UNION ALL SELECT value + 1 FROM n WHERE value < 1000)
+             INSERT INTO requests SELECT '/a', value FROM n UNION ALL SELECT '/b', value * 2 FROM n;
+             INSERT INTO requests VALUES ('/b', NULL), ('/c', NULL);",
+        )
+        .unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_aggregate_and_estimate() {
+        let conn = connection();
+
+        let blob: Vec<u8> = conn
+            .query_row(
+                "SELECT tdigest(latency, 50) FROM requests WHERE route = '/b'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        let t = TDigest::from_bytes(&blob).unwrap();
+        assert_eq!(t.count(), 1000.0);
+        assert_eq!(t.max_size(), 50);
+        assert_eq!(t.max(), 2000.0);
+
+        let (p99, cdf): (f64, f64) = conn
+            .query_row(
+                "SELECT tdigest_quantile(d, 0.99), tdigest_cdf(d, 1000)
+                 FROM (SELECT tdigest(latency) AS d FROM requests WHERE route = '/b')",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        let expected: f64 = 1980.0;
+        let percentage: f64 = (expected - p99).abs() / expected;
+        assert!(percentage < 0.01);
+        assert!((cdf - 0.5).abs() < 0.01);
+
+        let nulls: (Option<Vec<u8>>, Option<f64>, Option<f64>) = conn
+            .query_row(
+                "SELECT tdigest(latency), tdigest_quantile(NULL, 0.5), tdigest_cdf(tdigest(latency), 1)
+                 FROM requests WHERE route = '/c'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(nulls, (None, None, None));
+    }
+
+    #[test]
+    fn test_merge() {
+        let conn = connection();
+
+        let merged: Vec<u8> = conn
+            .query_row(
+                "SELECT tdigest_merge(d) FROM (SELECT tdigest(latency) AS d FROM requests GROUP BY route)",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        let t = TDigest::from_bytes(&merged).unwrap();
+        assert_eq!(t.count(), 2000.0);
+        assert_eq!((t.min(), t.max()), (1.0, 2000.0));
+
+        // Digests produced outside of SQLite merge with those produced in it.
+        let other = TDigest::new_with_size(100).merge_sorted(vec![5000.0; 2000]);
+        let p75: f64 = conn
+            .query_row(
+                "SELECT tdigest_quantile(tdigest_merge(d), 0.75)
+                 FROM (SELECT tdigest(latency) AS d FROM requests UNION ALL SELECT ?1)",
+                [other.to_bytes()],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(p75, 5000.0);
+
+        let many: f64 = conn
+            .query_row(
+                "SELECT tdigest_quantile(tdigest_merge(d), 1.0)
+                 FROM (SELECT tdigest(latency) AS d FROM requests GROUP BY latency)",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(many, 2000.0);
+    }
+
+    #[test]
+    fn test_errors() {
+        let conn = connection();
+        let query = |sql: &str| conn.query_row(sql, [], |row| row.get::<_, Option<f64>>(0));
+
+        assert!(query("SELECT tdigest_quantile(tdigest(latency), 1.5) FROM requests").is_err());
+        assert!(query("SELECT tdigest_quantile(x'00', 0.5)").is_err());
+        assert!(query("SELECT tdigest_cdf('text', 0.5)").is_err());
+        for sql in [
+            "SELECT tdigest(latency, 0) FROM requests",
+            "SELECT tdigest_merge(tdigest(latency, 1000000000000)) FROM requests",
+        ] {
+            assert!(
+                conn.query_row(sql, [], |row| row.get::<_, Vec<u8>>(0)).is_err(),
+                "{}",
+                sql
+            );
+        }
+    }
+}
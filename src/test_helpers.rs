@@ -0,0 +1,21 @@
+//! Fixtures shared by the tests of the encodings.
+
+use crate::{Centroid, TDigest};
+
+/// Decodes a string of hexadecimal digit pairs.
+pub(crate) fn hex(s: &str) -> Vec<u8> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+/// A digest of `max_size` 100 with the centroids 1, 2 twice and 3.
+pub(crate) fn digest() -> TDigest {
+    let centroids = vec![
+        Centroid::new(1.0, 1.0),
+        Centroid::new(2.0, 2.0),
+        Centroid::new(3.0, 1.0),
+    ];
+    TDigest::new(centroids, 8.0, 4.0, 3.0, 1.0, 100)
+}
@@ -0,0 +1,379 @@
+//! A `tower` middleware recording request latencies into digests, behind the `use_tower`
+//! feature.
+//!
+//! Each request is timed from the call to the inner service until its response future
+//! resolves, and recorded in seconds into a digest keyed by route and status class. The route
+//! is the request path unless `LatencyLayer::with_route` says otherwise: with axum, the
+//! `MatchedPath` extension avoids a digest per distinct path.
+//!
+//! ```rust
+//! use tdigest::tower::{LatencyLayer, StatusClass};
+//! use tower::{service_fn, Layer, ServiceExt};
+//!
+//! # tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(async {
+//! let layer = LatencyLayer::new(100);
+//! let handle = layer.handle();
+//! let service = layer.layer(service_fn(|_: http::Request<()>| async {
+//!     Ok::<_, std::convert::Infallible>(http::Response::new(()))
+//! }));
+//!
+//! service.oneshot(http::Request::get("/users").body(()).unwrap()).await.unwrap();
+//!
+//! let snapshot = handle.drain(&[0.5, 0.99]);
+//! assert_eq!(snapshot[0].route, "/users");
+//! assert_eq!(snapshot[0].status, StatusClass::Success);
+//! assert!(handle.snapshot(&[0.5]).is_empty());
+//! # });
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use http::request::Parts;
+use http::{Request, Response, StatusCode};
+use pin_project_lite::pin_project;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::sync::lock;
+use crate::TDigest;
+
+/// Adds a `LatencyService` recording into digests of `max_size`.
+#[derive(Clone)]
+pub struct LatencyLayer {
+    max_size: usize,
+    route: Arc<RouteFn>,
+    registry: Arc<Registry>,
+}
+
+/// Times the requests to `S`, see `LatencyLayer`.
+#[derive(Clone)]
+pub struct LatencyService<S> {
+    inner: S,
+    max_size: usize,
+    route: Arc<RouteFn>,
+    registry: Arc<Registry>,
+}
+
+/// Reads and drains the digests of a `LatencyLayer`.
+#[derive(Debug, Clone)]
+pub struct LatencyHandle {
+    registry: Arc<Registry>,
+}
+
+/// Class of the response status, or `Error` when the inner service failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum StatusClass {
+    Informational,
+    Success,
+    Redirection,
+    ClientError,
+    ServerError,
+    Error,
+}
+
+/// Latencies of the requests to a route with a status class.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteSnapshot {
+    pub route: String,
+    pub status: StatusClass,
+    /// Each requested quantile with its estimate, in seconds.
+    pub quantiles: Vec<(f64, f64)>,
+    pub digest: TDigest,
+}
+
+pin_project! {
+    /// Response future of a `LatencyService`.
+    pub struct ResponseFuture<F> {
+        #[pin]
+        inner: F,
+        start: Instant,
+        route: Option<String>,
+        max_size: usize,
+        registry: Arc<Registry>,
+    }
+}
+
+type RouteFn = dyn Fn(&Parts) -> String + Send + Sync;
+
+type Registry = Mutex<HashMap<(String, StatusClass), TDigest>>;
+
+impl LatencyLayer {
+    pub fn new(max_size: usize) -> Self {
+        LatencyLayer {
+            max_size,
+            route: Arc::new(|parts: &Parts| parts.uri.path().to_string()),
+            registry: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Keys the digests by the route `route` returns for each request.
+    pub fn with_route<F>(mut self, route: F) -> Self
+    where
+        F: Fn(&Parts) -> String + Send + Sync + 'static,
+    {
+        self.route = Arc::new(route);
+        self
+    }
+
+    pub fn handle(&self) -> LatencyHandle {
+        LatencyHandle {
+            registry: Arc::clone(&self.registry),
+        }
+    }
+}
+
+impl fmt::Debug for LatencyLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LatencyLayer")
+            .field("max_size", &self.max_size)
+            .finish()
+    }
+}
+
+impl<S> Layer<S> for LatencyLayer {
+    type Service = LatencyService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        LatencyService {
+            inner,
+            max_size: self.max_size,
+            route: Arc::clone(&self.route),
+            registry: Arc::clone(&self.registry),
+        }
+    }
+}
+
+impl<S: fmt::Debug> fmt::Debug for LatencyService<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LatencyService")
+            .field("inner", &self.inner)
+            .field("max_size", &self.max_size)
+            .finish()
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for LatencyService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<ReqBody>) -> Self::Future {
+        let (parts, body) = request.into_parts();
+        let route = (self.route)(&parts);
+
+        ResponseFuture {
+            inner: self.inner.call(Request::from_parts(parts, body)),
+            start: Instant::now(),
+            route: Some(route),
+            max_size: self.max_size,
+            registry: Arc::clone(&self.registry),
+        }
+    }
+}
+
+impl<F, ResBody, E> Future for ResponseFuture<F>
+where
+    F: Future<Output = Result<Response<ResBody>, E>>,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let result = match this.inner.poll(cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => return Poll::Pending,
+        };
+
+        if let Some(route) = this.route.take() {
+            let status = match &result {
+                Ok(response) => StatusClass::from(response.status()),
+                Err(_) => StatusClass::Error,
+            };
+
+            lock(this.registry)
+                .entry((route, status))
+                .or_insert_with(|| TDigest::new_with_size(*this.max_size))
+                .insert(this.start.elapsed().as_secs_f64());
+        }
+
+        Poll::Ready(result)
+    }
+}
+
+impl<F> fmt::Debug for ResponseFuture<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResponseFuture")
+            .field("start", &self.start)
+            .field("route", &self.route)
+            .finish()
+    }
+}
+
+impl From<StatusCode> for StatusClass {
+    fn from(status: StatusCode) -> Self {
+        match status.as_u16() {
+            100..=199 => StatusClass::Informational,
+            200..=299 => StatusClass::Success,
+            300..=399 => StatusClass::Redirection,
+            400..=499 => StatusClass::ClientError,
+            _ => StatusClass::ServerError,
+        }
+    }
+}
+
+impl fmt::Display for StatusClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let class = match self {
+            StatusClass::Informational => "1xx",
+            StatusClass::Success => "2xx",
+            StatusClass::Redirection => "3xx",
+            StatusClass::ClientError => "4xx",
+            StatusClass::ServerError => "5xx",
+            StatusClass::Error => "error",
+        };
+        f.write_str(class)
+    }
+}
+
+impl LatencyHandle {
+    /// Flushes and copies every digest, sorted by route then status class, with their estimates
+    /// of `quantiles`.
+    pub fn snapshot(&self, quantiles: &[f64]) -> Vec<RouteSnapshot> {
+        let digests: Vec<((String, StatusClass), TDigest)> = lock(&self.registry)
+            .iter_mut()
+            .map(|(key, digest)| {
+                digest.flush();
+                (key.clone(), digest.clone())
+            })
+            .collect();
+        Self::summarize(digests, quantiles)
+    }
+
+    /// Like `snapshot`, but also removes the digests so the next interval starts empty.
+    pub fn drain(&self, quantiles: &[f64]) -> Vec<RouteSnapshot> {
+        let digests = mem::take(&mut *lock(&self.registry));
+        Self::summarize(digests, quantiles)
+    }
+
+    fn summarize(
+        digests: impl IntoIterator<Item = ((String, StatusClass), TDigest)>,
+        quantiles: &[f64],
+    ) -> Vec<RouteSnapshot> {
+        let mut snapshot: Vec<RouteSnapshot> = digests
+            .into_iter()
+            .map(|((route, status), mut digest)| {
+                digest.flush();
+                RouteSnapshot {
+                    route,
+                    status,
+                    quantiles: quantiles
+                        .iter()
+                        .cloned()
+                        .zip(digest.estimate_quantiles(quantiles))
+                        .collect(),
+                    digest,
+                }
+            })
+            .collect();
+
+        snapshot.sort_by(|a, b| (&a.route, a.status).cmp(&(&b.route, b.status)));
+        snapshot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::tower::{service_fn, ServiceExt};
+    use std::time::Duration;
+
+    async fn mock(request: Request<()>) -> Result<Response<()>, &'static str> {
+        let status = match request.uri().path() {
+            "/slow" => {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                StatusCode::OK
+            }
+            "/fail" => return Err("failed"),
+            "/users/1" | "/users/2" => StatusCode::OK,
+            _ => StatusCode::NOT_FOUND,
+        };
+
+        let mut response = Response::new(());
+        *response.status_mut() = status;
+        Ok(response)
+    }
+
+    fn get(path: &str) -> Request<()> {
+        Request::get(path).body(()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_records_per_route_and_status() {
+        let layer = LatencyLayer::new(100);
+        let handle = layer.handle();
+        let service = layer.layer(service_fn(mock));
+
+        for _ in 0..3 {
+            service.clone().oneshot(get("/slow")).await.unwrap();
+        }
+        service.clone().oneshot(get("/missing")).await.unwrap();
+        assert!(service.clone().oneshot(get("/fail")).await.is_err());
+
+        let snapshot = handle.snapshot(&[0.5]);
+        let keys: Vec<(&str, StatusClass)> = snapshot.iter().map(|s| (s.route.as_str(), s.status)).collect();
+        assert_eq!(
+            keys,
+            vec![
+                ("/fail", StatusClass::Error),
+                ("/missing", StatusClass::ClientError),
+                ("/slow", StatusClass::Success),
+            ]
+        );
+        assert_eq!(snapshot[2].digest.count(), 3.0);
+        assert!(snapshot[2].digest.min() >= 0.020);
+        assert!(snapshot[2].quantiles[0].1 >= 0.020);
+        assert_eq!(snapshot[2].status.to_string(), "2xx");
+
+        let drained = handle.drain(&[0.5]);
+        assert_eq!(drained, snapshot);
+        assert!(handle.snapshot(&[0.5]).is_empty());
+
+        service.oneshot(get("/slow")).await.unwrap();
+        assert_eq!(handle.snapshot(&[])[0].digest.count(), 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_custom_route() {
+        let layer = LatencyLayer::new(100).with_route(|parts: &Parts| {
+            let path = parts.uri.path();
+            match path.strip_prefix("/users/") {
+                Some(_) => "/users/:id".to_string(),
+                None => path.to_string(),
+            }
+        });
+        let handle = layer.handle();
+        let service = layer.layer(service_fn(mock));
+
+        service.clone().oneshot(get("/users/1")).await.unwrap();
+        service.oneshot(get("/users/2")).await.unwrap();
+
+        let snapshot = handle.snapshot(&[]);
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].route, "/users/:id");
+        assert_eq!(snapshot[0].digest.count(), 2.0);
+    }
+}
@@ -0,0 +1,299 @@
+//! A `tracing_subscriber` layer recording span latencies into digests, behind the
+//! `use_tracing` feature.
+//!
+//! Each closed span contributes its busy time, spent entered, and its idle time, spent
+//! between creation, entries and close, both in seconds. They are recorded into digests keyed
+//! by the span name and the values of the selected fields.
+//!
+//! ```rust
+//! use tdigest::tracing::SpanLatencyLayer;
+//! use tracing_subscriber::layer::SubscriberExt;
+//!
+//! let layer = SpanLatencyLayer::new(100).with_fields(&["route"]);
+//! let handle = layer.handle();
+//! let subscriber = tracing_subscriber::registry().with(layer);
+//!
+//! tracing::subscriber::with_default(subscriber, || {
+//!     tracing::info_span!("request", route = "/").in_scope(|| {});
+//! });
+//!
+//! let report = handle.report();
+//! assert_eq!(report[0].busy.count, 1.0);
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use tracing_core::field::{Field, Visit};
+use tracing_core::span::{Attributes, Id, Record};
+use tracing_core::Subscriber;
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::sync::lock;
+use crate::TDigest;
+
+/// Records span latencies into per-span digests of `max_size`.
+#[derive(Debug)]
+pub struct SpanLatencyLayer {
+    max_size: usize,
+    fields: Vec<&'static str>,
+    registry: Arc<Registry>,
+}
+
+/// Reads the digests of a `SpanLatencyLayer`.
+#[derive(Debug, Clone)]
+pub struct SpanLatencyHandle {
+    registry: Arc<Registry>,
+}
+
+/// Latencies of the spans sharing a name and selected field values.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpanReport {
+    pub name: &'static str,
+    /// The selected fields recorded on the spans, in the order they were selected.
+    pub fields: Vec<(&'static str, String)>,
+    pub busy: LatencyReport,
+    pub idle: LatencyReport,
+}
+
+/// Summary of a latency digest, in seconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatencyReport {
+    pub count: f64,
+    pub min: f64,
+    pub max: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+}
+
+type SpanKey = (&'static str, Vec<(&'static str, String)>);
+
+type Registry = Mutex<HashMap<SpanKey, Latencies>>;
+
+#[derive(Debug)]
+struct Latencies {
+    busy: TDigest,
+    idle: TDigest,
+}
+
+/// Stored in the extensions of each span while it is open.
+struct Timings {
+    fields: Vec<(&'static str, String)>,
+    busy: f64,
+    idle: f64,
+    last: Instant,
+}
+
+/// Collects the values of the selected fields.
+struct FieldVisitor<'a> {
+    selected: &'a [&'static str],
+    fields: &'a mut Vec<(&'static str, String)>,
+}
+
+impl<'a> FieldVisitor<'a> {
+    fn set(&mut self, field: &Field, value: String) {
+        let Some(&name) = self.selected.iter().find(|&&name| name == field.name()) else {
+            return;
+        };
+
+        match self.fields.iter_mut().find(|(n, _)| *n == name) {
+            Some(entry) => entry.1 = value,
+            None => self.fields.push((name, value)),
+        }
+    }
+}
+
+impl<'a> Visit for FieldVisitor<'a> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field, value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.set(field, format!("{:?}", value));
+    }
+}
+
+impl SpanLatencyLayer {
+    pub fn new(max_size: usize) -> Self {
+        SpanLatencyLayer {
+            max_size,
+            fields: Vec::new(),
+            registry: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Keys the digests by the values of `fields` in addition to the span name.
+    pub fn with_fields(mut self, fields: &[&'static str]) -> Self {
+        self.fields = fields.to_vec();
+        self
+    }
+
+    pub fn handle(&self) -> SpanLatencyHandle {
+        SpanLatencyHandle {
+            registry: Arc::clone(&self.registry),
+        }
+    }
+
+    fn visit(&self, fields: &mut Vec<(&'static str, String)>, record: impl FnOnce(&mut dyn Visit)) {
+        if self.fields.is_empty() {
+            return;
+        }
+
+        let mut visitor = FieldVisitor {
+            selected: &self.fields,
+            fields,
+        };
+        record(&mut visitor);
+    }
+}
+
+impl<S> Layer<S> for SpanLatencyLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+
+        let mut fields: Vec<(&'static str, String)> = Vec::new();
+        self.visit(&mut fields, |visitor| attrs.record(visitor));
+        span.extensions_mut().insert(Timings {
+            fields,
+            busy: 0.0,
+            idle: 0.0,
+            last: Instant::now(),
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+
+        let mut extensions = span.extensions_mut();
+        if let Some(timings) = extensions.get_mut::<Timings>() {
+            self.visit(&mut timings.fields, |visitor| values.record(visitor));
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+
+        let mut extensions = span.extensions_mut();
+        if let Some(timings) = extensions.get_mut::<Timings>() {
+            let now = Instant::now();
+            timings.idle += (now - timings.last).as_secs_f64();
+            timings.last = now;
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+
+        let mut extensions = span.extensions_mut();
+        if let Some(timings) = extensions.get_mut::<Timings>() {
+            let now = Instant::now();
+            timings.busy += (now - timings.last).as_secs_f64();
+            timings.last = now;
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else { return };
+        let Some(mut timings) = span.extensions_mut().remove::<Timings>() else {
+            return;
+        };
+        timings.idle += timings.last.elapsed().as_secs_f64();
+
+        // Keep the selected fields in the order they were selected, whatever the recording order.
+        let mut fields = timings.fields;
+        fields.sort_by_key(|(name, _)| self.fields.iter().position(|f| f == name));
+
+        let mut registry = lock(&self.registry);
+        let latencies = registry.entry((span.name(), fields)).or_insert_with(|| Latencies {
+            busy: TDigest::new_with_size(self.max_size),
+            idle: TDigest::new_with_size(self.max_size),
+        });
+        latencies.busy.insert(timings.busy);
+        latencies.idle.insert(timings.idle);
+    }
+}
+
+impl SpanLatencyHandle {
+    /// Summarizes the latencies recorded so far, sorted by span name then field values.
+    pub fn report(&self) -> Vec<SpanReport> {
+        let mut report: Vec<SpanReport> = lock(&self.registry)
+            .iter()
+            .map(|((name, fields), latencies)| SpanReport {
+                name,
+                fields: fields.clone(),
+                busy: LatencyReport::new(&latencies.busy),
+                idle: LatencyReport::new(&latencies.idle),
+            })
+            .collect();
+
+        report.sort_by(|a, b| (a.name, &a.fields).cmp(&(b.name, &b.fields)));
+        report
+    }
+}
+
+impl LatencyReport {
+    fn new(digest: &TDigest) -> Self {
+        let quantiles = digest.estimate_quantiles(&[0.5, 0.9, 0.99]);
+        LatencyReport {
+            count: digest.count(),
+            min: digest.min(),
+            max: digest.max(),
+            p50: quantiles[0],
+            p90: quantiles[1],
+            p99: quantiles[2],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_records_busy_and_idle_time() {
+        let layer = SpanLatencyLayer::new(100).with_fields(&["route", "status"]);
+        let handle = layer.handle();
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        ::tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..3 {
+                let span = ::tracing::info_span!("request", route = "/a", status = ::tracing::field::Empty, id = 1);
+                thread::sleep(Duration::from_millis(5));
+                span.in_scope(|| thread::sleep(Duration::from_millis(10)));
+                span.record("status", 200);
+            }
+
+            ::tracing::info_span!("request", route = "/b").in_scope(|| {});
+            ::tracing::info_span!("other").in_scope(|| {});
+        });
+
+        let report = handle.report();
+        assert_eq!(report.len(), 3);
+
+        assert_eq!(report[0].name, "other");
+        assert!(report[0].fields.is_empty());
+
+        assert_eq!(report[1].name, "request");
+        assert_eq!(
+            report[1].fields,
+            vec![("route", "/a".to_string()), ("status", "200".to_string())]
+        );
+        assert_eq!(report[1].busy.count, 3.0);
+        assert!(report[1].busy.min >= 0.010);
+        assert!(report[1].idle.min >= 0.005);
+        assert!(report[1].busy.p50 <= report[1].busy.max);
+
+        assert_eq!(report[2].fields, vec![("route", "/b".to_string())]);
+        assert_eq!(report[2].idle.count, 1.0);
+    }
+}
@@ -0,0 +1,234 @@
+//! Queries over an encoded digest without decoding it, see `TDigestView`.
+
+use std::fmt;
+
+use crate::encoding::{decode, Centroids};
+use crate::{Centroid, Scale, TDigest, TDigestError};
+
+/// Read-only digest borrowing the bytes produced by `TDigest::to_bytes`.
+///
+/// The bytes are fully validated once by `new`, then every query decodes the centroids it
+/// needs on the fly, without allocating. Answers match those of the decoded `TDigest`, up to
+/// rounding when the weights are not integers.
+#[derive(Clone)]
+pub struct TDigestView<'a> {
+    centroids: Centroids<'a>,
+    len: usize,
+    max_size: usize,
+    scale: Scale,
+    sum: f64,
+    count: f64,
+    max: f64,
+    min: f64,
+}
+
+impl<'a> TDigestView<'a> {
+    /// Validates `bytes` as strictly as `TDigest::from_bytes` does.
+    pub fn new(bytes: &'a [u8]) -> Result<Self, TDigestError> {
+        let (header, centroids) = decode(bytes)?;
+
+        let mut means: Option<(f64, f64)> = None;
+        for centroid in centroids.clone() {
+            let mean = centroid?.mean();
+            means = Some((means.map_or(mean, |(first, _)| first), mean));
+        }
+
+        let (min, max) = header.bounds(means);
+        let view = TDigestView {
+            centroids,
+            len: header.len,
+            max_size: header.max_size,
+            scale: header.scale,
+            sum: header.sum,
+            count: header.count,
+            max,
+            min,
+        };
+        TDigest::check_parts(view.centroids(), view.sum, view.count, max, min, view.max_size)?;
+        Ok(view)
+    }
+
+    #[inline]
+    pub fn count(&self) -> f64 {
+        self.count
+    }
+
+    #[inline]
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    #[inline]
+    pub fn min(&self) -> f64 {
+        self.min
+    }
+
+    #[inline]
+    pub fn max(&self) -> f64 {
+        self.max
+    }
+
+    #[inline]
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    #[inline]
+    pub fn scale(&self) -> Scale {
+        self.scale
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Decodes the centroids one at a time.
+    pub fn centroids(&self) -> impl Iterator<Item = Centroid> + 'a {
+        // The bytes were validated by `new`, so decoding cannot fail.
+        self.centroids.clone().map_while(Result::ok)
+    }
+
+    /// Same as `TDigest::estimate_quantile`.
+    pub fn estimate_quantile(&self, q: f64) -> f64 {
+        if self.is_empty() {
+            return 0.0;
+        } else if q <= 0.0 {
+            return self.min;
+        } else if q >= 1.0 {
+            return self.max;
+        }
+
+        let rank: f64 = q * self.count;
+        let mut t: f64 = 0.0;
+        let mut prev: Option<f64> = None;
+        let mut centroids = self.centroids();
+        while let Some(centroid) = centroids.next() {
+            if rank < t + centroid.weight() {
+                let next: Option<f64> = centroids.next().map(|c| c.mean());
+                return TDigest::interpolate(prev, &centroid, next, t, rank, (self.min, self.max));
+            }
+
+            t += centroid.weight();
+            prev = Some(centroid.mean());
+        }
+
+        // Only reached when rounding puts `rank` past the last centroid, which is interpolated
+        // from the total weight like `TDigest` does.
+        let (before_last, last) = self.last_two();
+        TDigest::interpolate(before_last, &last, None, t, rank, (self.min, self.max))
+    }
+
+    /// Same as `TDigest::estimate_cdf`.
+    pub fn estimate_cdf(&self, x: f64) -> f64 {
+        if self.count > 0.0 {
+            self.estimate_rank(x) / self.count
+        } else {
+            0.0
+        }
+    }
+
+    /// Same as `TDigest::estimate_rank`.
+    pub fn estimate_rank(&self, x: f64) -> f64 {
+        crate::RankCursor::from_parts(self.centroids(), self.count, self.max, self.min).rank(x)
+    }
+
+    /// Decodes the view into an owned digest.
+    pub fn to_tdigest(&self) -> TDigest {
+        let centroids: Vec<Centroid> = self.centroids().collect();
+        TDigest::new(centroids, self.sum, self.count, self.max, self.min, self.max_size).with_scale(self.scale)
+    }
+
+    /// Mean of the centroid before the last one, if any, and the last centroid.
+    fn last_two(&self) -> (Option<f64>, Centroid) {
+        let mut before_last: Option<f64> = None;
+        let mut last: Option<Centroid> = None;
+        for centroid in self.centroids() {
+            before_last = last.as_ref().map(|c| c.mean());
+            last = Some(centroid);
+        }
+
+        (before_last, last.unwrap_or_default())
+    }
+}
+
+impl<'a> fmt::Debug for TDigestView<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TDigestView")
+            .field("len", &self.len)
+            .field("max_size", &self.max_size)
+            .field("scale", &self.scale)
+            .field("sum", &self.sum)
+            .field("count", &self.count)
+            .field("max", &self.max)
+            .field("min", &self.min)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Precision;
+
+    fn digest() -> TDigest {
+        TDigest::new_with_size(100).merge_unsorted((1..=100_000).map(|v| f64::from(v).ln()).collect())
+    }
+
+    #[test]
+    fn test_matches_decoded_digest() {
+        let t = digest();
+        let bytes = t.to_bytes();
+        let view = TDigestView::new(&bytes).unwrap();
+
+        assert_eq!(view.count(), t.count());
+        assert_eq!(view.sum(), t.sum());
+        assert_eq!(view.min(), t.min());
+        assert_eq!(view.max(), t.max());
+        assert!(view.centroids().eq(t.centroids().iter().cloned()));
+
+        for i in 0..=100 {
+            let q = f64::from(i) / 100.0;
+            assert_eq!(view.estimate_quantile(q), t.estimate_quantile(q));
+
+            let x = t.min() + (t.max() - t.min()) * q;
+            assert_eq!(view.estimate_cdf(x), t.estimate_cdf(x));
+        }
+
+        assert_eq!(view.to_tdigest(), t);
+    }
+
+    #[test]
+    fn test_single_precision_and_small_digests() {
+        let t = digest();
+        let bytes = t.to_bytes_with_precision(Precision::F32);
+        let view = TDigestView::new(&bytes).unwrap();
+        let decoded = TDigest::from_bytes(&bytes).unwrap();
+        assert_eq!(view.estimate_quantile(0.99), decoded.estimate_quantile(0.99));
+        assert_eq!(view.min(), decoded.min());
+
+        for values in [vec![], vec![3.0], vec![3.0, 5.0], vec![1.0, 2.0, 2.0, 7.0]] {
+            let t = TDigest::new_with_size(10).merge_unsorted(values);
+            let bytes = t.to_bytes();
+            let view = TDigestView::new(&bytes).unwrap();
+            for q in [0.0, 0.1, 0.5, 0.75, 1.0] {
+                assert_eq!(view.estimate_quantile(q), t.estimate_quantile(q));
+                assert_eq!(view.estimate_rank(q * 8.0), t.estimate_rank(q * 8.0));
+            }
+        }
+    }
+
+    #[test]
+    fn test_rejects_corrupt_input() {
+        let bytes = digest().to_bytes();
+        assert!(TDigestView::new(&bytes[..bytes.len() - 1]).is_err());
+        assert!(TDigestView::new(&bytes[1..]).is_err());
+
+        let mut corrupt = bytes.clone();
+        corrupt[40] ^= 1;
+        assert_eq!(
+            TDigestView::new(&corrupt).err(),
+            Some(TDigestError::InvalidEncoding("checksum mismatch"))
+        );
+    }
+}